@@ -1,8 +1,10 @@
 use std::{
     collections::HashMap,
+    error::Error,
     ops::ControlFlow,
+    path::Path,
     sync::mpsc::{channel, Receiver, Sender},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use eframe::{App, CreationContext};
@@ -15,34 +17,38 @@ use figment::{
 };
 
 use crate::application::{
-    backend::server::{AuthStatus, UsersStatus},
+    backend::{
+        backups::BackupView,
+        hostkeys::HostKey,
+        lock::LockStatus,
+        merge::Merge,
+        remotecommand::Operation,
+        server::{self, AuthMethod, AuthStatus, Escalation, Host, UsersStatus},
+    },
     errorapplication::ErrorApplication,
 };
 
 use self::backend::{
     backend_loop,
-    configuration::Configuration,
+    configuration::{self, Configuration},
     messages::{QueryMessage, ResponseMessage},
     server::Server,
-    sshwrapper::SSHWrapper,
+    sshconfig::SshConfig,
 };
 
 mod backend;
 mod errorapplication;
 mod login_ui;
 mod main_ui;
+mod value_editors;
 
+#[derive(Default)]
 enum Screen {
+    #[default]
     Login,
     Main,
 }
 
-impl Default for Screen {
-    fn default() -> Self {
-        Screen::Login
-    }
-}
-
 // Application struct
 pub struct Application {
     // TX Channel
@@ -52,6 +58,8 @@ pub struct Application {
     // Login username
     password: String,
     // Login password
+    passphrase: String,
+    // Private key passphrase
     root_password: String,
     // Root Password
     tx: Sender<QueryMessage>,
@@ -61,14 +69,17 @@ pub struct Application {
     servers: Vec<Server>,
     // Toasts 🍞
     toasts: Toasts,
+    // Servers waiting for the user to trust their host key
+    host_key_prompts: Vec<(Server, HostKey)>,
 }
 
 impl App for Application {
     fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
         self.toasts.show(ctx);
 
-        // Receive and process messages from the backend (different thread)
-        if let Ok(msg) = self.rx.try_recv() {
+        // Receive and process messages from the backend (different thread), the workers of several servers may have
+        // answered since the last frame
+        while let Ok(msg) = self.rx.try_recv() {
             match msg {
                 // Authentication related messages
                 ResponseMessage::AuthenticationResult {
@@ -76,10 +87,15 @@ impl App for Application {
                     success,
                     error,
                 } => {
-                    self.get_server(&server).map(|s| {
+                    if let Some(s) = self.get_server(&server) {
                         log::trace!("Setting server to not busy");
                         s.auth_status = success.into();
-                    });
+                        s.auth_error = if success {
+                            None
+                        } else {
+                            Some(error.clone().unwrap_or("Authentication failed".to_owned()))
+                        };
+                    }
 
                     if success {
                         log::trace!("Authentication result for server {server}: {success}");
@@ -99,10 +115,10 @@ impl App for Application {
                         let _ = self
                             .tx
                             .send(QueryMessage::QueryVirtualUsers(server.clone()));
-                        self.get_server(&server).map(|s| {
+                        if let Some(s) = self.get_server(&server) {
                             log::trace!("Setting server to busy again");
                             s.users_status = UsersStatus::Downloading;
-                        });
+                        }
                     } else {
                         self.show_notification(
                             format!("Authentication failed\nfor server {server}").into(),
@@ -114,21 +130,81 @@ impl App for Application {
                         );
                     }
                 }
+                // The server must be trusted before trying to authenticate again
+                ResponseMessage::UnknownHostKey { server, host_key } => {
+                    log::trace!("Unknown host key for server {server}: {host_key:?}");
+
+                    if let Some(s) = self.get_server(&server) {
+                        s.auth_status = AuthStatus::Unknown;
+                    }
+                    self.host_key_prompts.push((server, host_key));
+                }
+                // The accepted host key was stored, log in again with it
+                ResponseMessage::HostKeyTrusted { servers, error } => match error {
+                    None => self.authenticate_servers(servers),
+                    Some(error) => {
+                        for server in servers.iter() {
+                            if let Some(s) = self.get_server(server) {
+                                s.auth_status = AuthStatus::Failed;
+                                s.auth_error = Some(error.clone());
+                            }
+                        }
+                        self.show_notification(error.into(), ToastKind::Error);
+                    }
+                },
+                // A dead session is logged in again before the next operation on the host, for all its maps
+                ResponseMessage::ConnectionStatus {
+                    host,
+                    status,
+                    error,
+                } => {
+                    for s in self.get_host_servers(&host) {
+                        s.auth_status = status.clone();
+                        s.auth_error = error.clone();
+                    }
+
+                    match status {
+                        AuthStatus::Reconnecting => {
+                            log::warn!("Reconnecting to host {host}");
+                        }
+                        AuthStatus::Authenticated => {
+                            self.show_notification(
+                                format!("Reconnected to host {host}").into(),
+                                ToastKind::Info,
+                            );
+                        }
+                        // The operation that needed the session reports the error
+                        _ => {}
+                    }
+                }
+                ResponseMessage::HopsStatus { host, hops } => {
+                    for s in self.get_host_servers(&host) {
+                        s.hops = hops.clone();
+                    }
+                }
                 // Handle received virtual users hash
-                ResponseMessage::GotVirtualUsers { server, users } => {
+                ResponseMessage::GotVirtualUsers {
+                    server,
+                    users,
+                    report,
+                    snapshot,
+                } => {
                     log::trace!("Got virtual users\nfrom server {server}: {users:#?}");
 
                     // Match the received server instance with the server instances owned by the application
-                    self.get_server(&server).map(|s| {
-                        s.users = users;
+                    if let Some(s) = self.get_server(&server) {
+                        s.set_users(users);
+                        s.normalization = report;
+                        s.snapshot = snapshot;
+                        s.merge = None;
                         s.users_status = UsersStatus::Idle;
-                    });
+                    }
                 }
                 // Handle the case when the query fails
                 ResponseMessage::QueryVirtualUsersResult { server, error } => {
-                    self.get_server(&server).map(|s| {
+                    if let Some(s) = self.get_server(&server) {
                         s.users_status = UsersStatus::Unknown;
-                    });
+                    }
 
                     self.show_notification(
                         format!("Couldn't upload configuration\nto server {server}: {error}")
@@ -138,17 +214,115 @@ impl App for Application {
 
                     log::error!("Couldn't upload configuration to server {server}: {error}");
                 }
+                // The backups were listed or pruned
+                ResponseMessage::Backups {
+                    server,
+                    backups,
+                    error,
+                } => {
+                    if let Some(s) = self.get_server(&server) {
+                        s.backups = Some(backups);
+                    }
+
+                    if let Some(error) = error {
+                        self.show_notification(
+                            format!("Can't list the backups\nof server {server}: {error}").into(),
+                            ToastKind::Error,
+                        );
+                    }
+                }
+                // A backup was downloaded to compare it with the map
+                ResponseMessage::BackupContent {
+                    server,
+                    path,
+                    document,
+                    error,
+                } => {
+                    if let (Some(s), Some(document)) = (self.get_server(&server), document) {
+                        s.backup_view = Some(BackupView::new(path, document, &s.snapshot.document));
+                    }
+
+                    if let Some(error) = error {
+                        self.show_notification(
+                            format!("Can't download the backup\nfrom server {server}: {error}")
+                                .into(),
+                            ToastKind::Error,
+                        );
+                    }
+                }
+                // The lock was taken, released or found in someone else's hands
+                ResponseMessage::LockResult {
+                    server,
+                    lock,
+                    error,
+                } => {
+                    log::trace!("Lock of server {server}: {lock:?}");
+
+                    if let Some(s) = self.get_server(&server) {
+                        s.lock = lock;
+                    }
+
+                    if let Some(error) = error {
+                        self.show_notification(
+                            format!("Lock error on\nserver {server}: {error}").into(),
+                            ToastKind::Error,
+                        );
+                    }
+                }
+                // Someone else changed the map, merge it with the local changes and let the user review it
+                ResponseMessage::RemoteChanged { server, remote } => {
+                    log::warn!("The map on server {server} changed since it was downloaded");
+
+                    if let Some(s) = self.get_server(&server) {
+                        let ordered = s.format.format().ordered();
+                        s.merge = Some(Merge::new(&s.snapshot.document, remote, &s.users, ordered));
+                    }
+
+                    self.show_notification(
+                        format!("The map on server {server}\nwas changed by someone else").into(),
+                        ToastKind::Warning,
+                    );
+                }
                 // Handle the result of server configuration uploads
-                ResponseMessage::ServerUploadResult { error, server } => {
-                    self.get_server(&server).map(|s| {
+                ResponseMessage::ServerUploadResult {
+                    error,
+                    server,
+                    rolled_back,
+                    steps,
+                    snapshot,
+                } => {
+                    if let Some(s) = self.get_server(&server) {
                         s.users_status = UsersStatus::Idle;
-                    });
+                        if let Some(snapshot) = snapshot {
+                            s.snapshot = snapshot;
+                            if let Some(view) = &mut s.backup_view {
+                                view.compare(&s.snapshot.document);
+                            }
+                        }
+                        // A restored backup becomes the local map only once it's on the server
+                        if let Some(document) = s.restoring.take() {
+                            if error.is_none() {
+                                s.set_users(document);
+                            }
+                        }
+                        s.operations.push(Operation {
+                            time: chrono::Local::now().format("%H:%M:%S").to_string(),
+                            steps,
+                        });
+                    }
 
                     if let Some(error) = error {
                         log::error!("Error uploading data to server {server}: {error}");
 
+                        // Tell whether the server is still using the previous map
+                        let rollback = if rolled_back {
+                            "\nThe previous map was restored"
+                        } else {
+                            ""
+                        };
                         self.show_notification(
-                            format!("Error uploading data to\nserver {server}: {error}").into(),
+                            format!("Error uploading data to\nserver {server}: {error}{rollback}")
+                                .into(),
                             ToastKind::Error,
                         );
                     } else {
@@ -162,32 +336,72 @@ impl App for Application {
         match self.screen {
             Screen::Login => {
                 // Draw the login view
-                let _ = self.draw_login(&ctx, frame);
+                let _ = self.draw_login(ctx, frame);
             }
             Screen::Main => {
                 // Draw the main view
-                let _ = self.draw_main(&ctx, frame);
+                let _ = self.draw_main(ctx, frame);
+            }
+        }
+    }
+
+    // Release the locks still held, waiting a bit for the backend so they aren't left behind
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        let mut pending = 0;
+        for server in self.servers.iter() {
+            if let LockStatus::Ours(_) = server.lock {
+                let _ = self.tx.send(QueryMessage::Unlock(server.clone()));
+                pending += 1;
+            }
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while pending > 0 {
+            match self
+                .rx
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Ok(ResponseMessage::LockResult { .. }) => pending -= 1,
+                Ok(_) => {}
+                Err(_) => {
+                    log::warn!("{pending} locks couldn't be released");
+                    break;
+                }
             }
         }
     }
 }
 
+// Add the Host entries of ~/.ssh/config matching pattern to the servers of config.toml, managing the map at config_path
+pub fn import_ssh_config(pattern: &str, config_path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    configuration::import_ssh_hosts(
+        Path::new("config.toml"),
+        &SshConfig::load(),
+        pattern,
+        config_path,
+    )
+}
+
 impl Application {
     // Create a new instance of the application
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(ctx: &CreationContext) -> Box<dyn App> {
         // Load configuration from TOML
-        let config: Configuration = match Figment::new().merge(Toml::file("config.toml")).extract()
-        {
-            Ok(config) => config,
-            Err(error) => {
-                log::error!("Error loading configuration: {error:#?}");
-
-                return ErrorApplication::new(format!(
-                    "Error loading configuration: {}",
-                    error.to_string()
-                ));
-            }
-        };
+        let mut config: Configuration =
+            match Figment::new().merge(Toml::file("config.toml")).extract() {
+                Ok(config) => config,
+                Err(error) => {
+                    log::error!("Error loading configuration: {error:#?}");
+
+                    return ErrorApplication::new(format!("Error loading configuration: {error}"));
+                }
+            };
+
+        // Addresses may be Host aliases of the ssh config
+        let ssh_config = SshConfig::load();
+        for server in config.servers.iter_mut() {
+            server.resolve(&ssh_config);
+        }
 
         // Spawn a thread that forces to update the ui every 200ms
         log::trace!("Spawning extra update thread");
@@ -210,14 +424,13 @@ impl Application {
 
         // Launch the backend in a different thread
         std::thread::spawn(move || {
-            // Create the session hash here to make it persistent between loop iterations
-            let mut ssh_sessions: HashMap<String, SSHWrapper> = Default::default();
+            // The channels of the server workers, kept between loop iterations
+            let mut workers: HashMap<String, Sender<QueryMessage>> = Default::default();
 
             // Launch the backend loop
             loop {
                 // The loop must be able to stop itself from within, so it returns a ControlFlow
-                if let ControlFlow::Break(_) =
-                    backend_loop(&backend_rx, &backend_tx, &mut ssh_sessions)
+                if let ControlFlow::Break(_) = backend_loop(&backend_rx, &backend_tx, &mut workers)
                 {
                     return;
                 }
@@ -234,6 +447,8 @@ impl Application {
             username: config.username,
             // Login password
             password: String::from(""),
+            // Private key passphrase
+            passphrase: String::from(""),
             // Root Password
             root_password: String::from(""),
             // Current Screen
@@ -242,28 +457,82 @@ impl Application {
             servers: config.servers,
             // Toasts 🍞
             toasts: Toasts::new().anchor(Align2::RIGHT_TOP, Pos2::new(-5.0, 5.0)),
+            // No host keys to confirm yet
+            host_key_prompts: vec![],
         };
 
         Box::new(application)
     }
 
+    // Take the lock of a map when the user starts editing it
+    fn lock_for_editing(&mut self, index: usize) {
+        let server = &mut self.servers[index];
+        if server.lock == LockStatus::Unlocked {
+            server.lock = LockStatus::Requested;
+            let _ = self.tx.send(QueryMessage::Lock(server.clone()));
+        }
+    }
+
     fn get_server(&mut self, server: &Server) -> Option<&mut Server> {
-        for owned_server in self.servers.iter_mut() {
-            if owned_server == server {
-                return Some(owned_server);
+        self.servers
+            .iter_mut()
+            .find(|owned_server| *owned_server == server)
+    }
+
+    // The hosts of the maps in the order they are configured, with the positions of their maps
+    fn hosts(&self) -> Vec<(Host, Vec<usize>)> {
+        server::hosts(&self.servers)
+    }
+
+    // The maps on a host, they share its session
+    fn get_host_servers(&mut self, host: &Host) -> impl Iterator<Item = &mut Server> {
+        let host = host.to_string();
+        self.servers
+            .iter_mut()
+            .filter(move |server| server.host().to_string() == host)
+    }
+
+    // Send the credentials to the backend to authenticate with some servers
+    fn authenticate_servers(&mut self, servers: Vec<Server>) {
+        for server in servers.iter() {
+            if let Some(s) = self.get_server(server) {
+                s.auth_status = AuthStatus::InProgress;
+                s.auth_error = None;
             }
         }
 
-        None
+        let _ = self.tx.send(QueryMessage::Authenticate {
+            username: self.username.clone(),
+            password: self.password.clone(),
+            passphrase: self.passphrase.clone(),
+            servers,
+            root_password: self.root_password.clone(),
+        });
     }
 
     // Get whether the current data is valid to allow the user click the Ok button
     fn login_form_is_valid(&mut self) -> bool {
-        !(self.password.trim().is_empty()
-            || self.root_password.trim().is_empty()
+        !((self.needs_auth_method(AuthMethod::Password) && self.password.trim().is_empty())
+            || (self.needs_escalation(Escalation::Su) && self.root_password.trim().is_empty())
             || self.username.trim().is_empty())
     }
 
+    // Get whether any server still to be authenticated uses an authentication method
+    fn needs_auth_method(&self, method: AuthMethod) -> bool {
+        self.servers
+            .iter()
+            .filter(|server| server.auth_status != AuthStatus::Authenticated)
+            .flat_map(|server| server.host().hops(&self.username))
+            .any(|hop| hop.auth == method)
+    }
+
+    // Get whether any server still to be authenticated becomes root with an escalation method
+    fn needs_escalation(&self, escalation: Escalation) -> bool {
+        self.servers.iter().any(|server| {
+            server.escalation == escalation && server.auth_status != AuthStatus::Authenticated
+        })
+    }
+
     // Helper to show notifications
     fn show_notification(&mut self, message: WidgetText, kind: ToastKind) {
         self.toasts.add(Toast {
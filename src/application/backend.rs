@@ -1,74 +1,205 @@
 use self::{
+    backups::Backup,
+    hostkeys::HostKeyError,
+    lock::LockStatus,
     messages::{QueryMessage, ResponseMessage},
-    sshwrapper::SSHWrapper,
+    remotecommand::{rolled_back, StepResult, UploadOutcome, UploadStep},
+    server::{AuthStatus, HopStatus, Host, Server},
+    sshwrapper::{SSHWrapper, KEEPALIVE_INTERVAL},
 };
 use std::{
     collections::HashMap,
+    error::Error,
     ops::ControlFlow,
-    sync::mpsc::{Receiver, Sender},
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, SendError, Sender},
+    time::Duration,
 };
 
+pub mod address;
+pub mod backups;
 pub mod configuration;
+pub mod hostkeys;
+pub mod lock;
+pub mod mapdocument;
+pub mod mapformat;
+pub mod merge;
 pub mod messages;
+pub mod remotecommand;
+pub mod resolver;
+pub mod reverseindex;
 pub mod server;
+pub mod sshconfig;
 pub mod sshwrapper;
+pub mod tunnel;
 
+// Receive the frontend's messages and hand them to the worker of their host, so operations on different hosts run
+// concurrently and the ones on the same host keep their order
 pub fn backend_loop(
     rx: &Receiver<QueryMessage>,
     tx: &Sender<ResponseMessage>,
-    ssh_sessions: &mut HashMap<String, SSHWrapper>,
+    workers: &mut HashMap<String, Sender<QueryMessage>>,
 ) -> ControlFlow<()> {
-    match rx.recv() {
-        Ok(msg) => match msg {
-            QueryMessage::QueryVirtualUsers(server) => {
-                log::trace!("Requested virtual users for server {server}");
-
-                if let Some(session) = ssh_sessions.get_mut(&server.to_string_extended()) {
-                    match session.get_virtual_users() {
-                        Ok(users) => {
-                            let _ = tx.send(ResponseMessage::GotVirtualUsers { server, users });
-                        }
-                        Err(error) => {
-                            log::error!("Error getting virtual users: {error:?}");
-                            let _ = tx.send(ResponseMessage::QueryVirtualUsersResult {
-                                server,
-                                error: error.to_string(),
-                            });
-                        }
+    let Ok(msg) = rx.recv() else {
+        // Dropping the workers' channels ends them
+        return ControlFlow::Break(());
+    };
+
+    match msg {
+        // Every host logs in on its own worker
+        QueryMessage::Authenticate {
+            username,
+            password,
+            passphrase,
+            servers,
+            root_password,
+        } => {
+            // The maps of the same host log in together, in the order they are configured
+            let mut hosts: Vec<(String, Vec<Server>)> = vec![];
+            for server in servers {
+                let key = server.host().to_string();
+                match hosts.iter_mut().find(|(host, _)| *host == key) {
+                    Some((_, maps)) => maps.push(server),
+                    None => hosts.push((key, vec![server])),
+                }
+            }
+
+            for (_, servers) in hosts {
+                dispatch(
+                    tx,
+                    workers,
+                    QueryMessage::Authenticate {
+                        username: username.clone(),
+                        password: password.clone(),
+                        passphrase: passphrase.clone(),
+                        servers,
+                        root_password: root_password.clone(),
+                    },
+                );
+            }
+        }
+        msg => dispatch(tx, workers, msg),
+    }
+
+    ControlFlow::Continue(())
+}
+
+// Send a message to the worker of its map's host, starting it if there is none or the last one died
+fn dispatch(
+    tx: &Sender<ResponseMessage>,
+    workers: &mut HashMap<String, Sender<QueryMessage>>,
+    msg: QueryMessage,
+) {
+    let key = msg.server().host().to_string();
+
+    let msg = match workers.get(&key) {
+        Some(worker) => match worker.send(msg) {
+            Ok(()) => return,
+            Err(SendError(msg)) => {
+                log::error!("The worker of {key} died, starting a new one");
+                msg
+            }
+        },
+        None => msg,
+    };
+
+    let (worker_tx, worker_rx) = channel();
+    let tx = tx.clone();
+    std::thread::spawn(move || worker_loop(&worker_rx, &tx));
+
+    let _ = worker_tx.send(msg);
+    workers.insert(key, worker_tx);
+}
+
+// Run the operations on the maps of one host in order, keeping their shared session alive between them
+fn worker_loop(rx: &Receiver<QueryMessage>, tx: &Sender<ResponseMessage>) {
+    let mut session: Option<SSHWrapper> = None;
+
+    loop {
+        // Wake up now and then to send the keepalives of an idle session
+        match rx.recv_timeout(Duration::from_secs(KEEPALIVE_INTERVAL as u64)) {
+            Ok(msg) => handle(msg, tx, &mut session),
+            Err(RecvTimeoutError::Timeout) => {
+                if let Some(session) = &mut session {
+                    session.keepalive();
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+// Run an operation on the session of its map's host
+fn handle(msg: QueryMessage, tx: &Sender<ResponseMessage>, session: &mut Option<SSHWrapper>) {
+    match msg {
+        QueryMessage::QueryVirtualUsers(server) => {
+            log::trace!("Requested virtual users for server {server}");
+
+            match connected_session(tx, session)
+                .and_then(|session| session.get_virtual_users(&server))
+            {
+                Ok(snapshot) => {
+                    let users = snapshot.document.clone();
+
+                    // Pattern tables are ordered rules, merging their entries would change their meaning
+                    let report = if server.format.format().ordered() {
+                        Default::default()
+                    } else {
+                        users.normalization_report()
+                    };
+                    if !report.is_empty() {
+                        log::warn!("Server {server} map needs normalization: {report:?}");
                     }
+
+                    let _ = tx.send(ResponseMessage::GotVirtualUsers {
+                        server,
+                        users,
+                        report,
+                        snapshot,
+                    });
+                }
+                Err(error) => {
+                    log::error!("Error getting virtual users: {error:?}");
+                    let _ = tx.send(ResponseMessage::QueryVirtualUsersResult {
+                        server,
+                        error: error.to_string(),
+                    });
                 }
             }
-            QueryMessage::Authenticate {
-                username,
-                password,
-                servers,
-                root_password,
-            } => {
-                for server in servers.iter() {
-                    let mut wrapper = SSHWrapper::new(
-                        server.clone(),
-                        username.clone(),
-                        password.clone(),
-                        root_password.clone(),
-                    );
-
-                    match wrapper.authenticate() {
-                        Ok(result) => {
-                            if result {
-                                let _ = tx.send(ResponseMessage::AuthenticationResult {
-                                    server: server.clone(),
-                                    success: true,
-                                    error: None,
-                                });
-                            } else {
-                                let _ = tx.send(ResponseMessage::AuthenticationResult {
-                                    server: server.to_owned(),
-                                    success: false,
-                                    error: None,
-                                });
-                            }
+        }
+        QueryMessage::Authenticate {
+            username,
+            password,
+            passphrase,
+            servers,
+            root_password,
+        } => {
+            // The maps are on the same host, one login serves all of them
+            let Some(host) = servers.first().map(|server| server.host()) else {
+                return;
+            };
+            let mut wrapper =
+                SSHWrapper::new(host.clone(), username, password, passphrase, root_password);
+            let result = wrapper.authenticate(report_hops(tx, &host));
+
+            for server in servers.iter() {
+                match &result {
+                    Ok(success) => {
+                        let _ = tx.send(ResponseMessage::AuthenticationResult {
+                            server: server.clone(),
+                            success: *success,
+                            error: None,
+                        });
+                    }
+                    // Unknown host keys must be confirmed by the user before trying again
+                    Err(error) => match error.downcast_ref::<HostKeyError>() {
+                        Some(HostKeyError::Unknown(host_key)) => {
+                            log::warn!("{error}");
+                            let _ = tx.send(ResponseMessage::UnknownHostKey {
+                                server: server.to_owned(),
+                                host_key: host_key.clone(),
+                            });
                         }
-                        Err(error) => {
+                        _ => {
                             log::error!("Authentication error: {error:?}");
                             let _ = tx.send(ResponseMessage::AuthenticationResult {
                                 server: server.to_owned(),
@@ -76,27 +207,218 @@ pub fn backend_loop(
                                 error: Some(error.to_string()),
                             });
                         }
-                    }
-                    ssh_sessions.insert(server.to_string_extended(), wrapper);
+                    },
                 }
             }
-            QueryMessage::UpdateVirtualUsers(server) => {
-                let server = &server;
-                for (key, session) in ssh_sessions {
-                    if &server.to_string_extended() == key {
-                        let error = session.upload_configuration(server.clone()).err();
+            *session = Some(wrapper);
+        }
+        QueryMessage::TrustHostKey { servers, host_key } => {
+            log::trace!("Trusting host key {host_key:?}");
 
-                        let _ = tx.send(ResponseMessage::ServerUploadResult {
-                            server: server.clone(),
-                            error: error.map(|x| x.to_string()),
-                        });
-                        break;
-                    }
+            // The frontend logs in again only after the key is in the file
+            let error = hostkeys::trust(&host_key).err().map(|error| {
+                log::error!("Can't store the host key: {error:?}");
+                format!("Can't store the host key: {error}")
+            });
+            let _ = tx.send(ResponseMessage::HostKeyTrusted { servers, error });
+        }
+        QueryMessage::UpdateVirtualUsers(server) => {
+            let server = &server;
+            let session = match connected_session(tx, session) {
+                Ok(session) => session,
+                Err(error) => {
+                    let step = StepResult::failed(UploadStep::Connect, error);
+                    let _ = tx.send(ResponseMessage::ServerUploadResult {
+                        server: server.clone(),
+                        error: Some(step.to_string()),
+                        rolled_back: false,
+                        steps: vec![step],
+                        snapshot: None,
+                    });
+                    return;
                 }
+            };
+
+            let UploadOutcome {
+                steps,
+                installed,
+                remote_changed,
+            } = session.upload_configuration(server);
+
+            // The upload stops at the first failed step
+            let error = steps
+                .iter()
+                .find(|step| !step.success())
+                .map(|step| step.to_string());
+
+            let _ = tx.send(ResponseMessage::ServerUploadResult {
+                server: server.clone(),
+                error: error.clone(),
+                rolled_back: rolled_back(&steps),
+                steps,
+                snapshot: installed,
+            });
+
+            // The upload made a new backup, which may leave older ones out of the retention policy
+            if error.is_none() {
+                send_backups(tx, server, session.prune_backups(server));
             }
-        },
-        Err(_) => return ControlFlow::Break(()),
+
+            // Saving ends the edition, release the lock
+            if let (None, LockStatus::Ours(owner)) = (&error, &server.lock) {
+                send_lock_result(
+                    tx,
+                    server,
+                    session.unlock(server, owner).map(|_| LockStatus::Unlocked),
+                );
+            }
+
+            // The user must merge the changes before uploading again
+            if let Some(remote) = remote_changed {
+                let _ = tx.send(ResponseMessage::RemoteChanged {
+                    server: server.clone(),
+                    remote,
+                });
+            }
+        }
+        QueryMessage::Lock(server) => {
+            let result = connected_session(tx, session).and_then(|session| session.lock(&server));
+            send_lock_result(tx, &server, result);
+        }
+        QueryMessage::Unlock(server) => {
+            if let LockStatus::Ours(owner) = &server.lock {
+                let result = connected_session(tx, session)
+                    .and_then(|session| session.unlock(&server, owner))
+                    .map(|_| LockStatus::Unlocked);
+                send_lock_result(tx, &server, result);
+            }
+        }
+        QueryMessage::BreakLock(server) => {
+            log::warn!("Breaking the lock of {server}: {:?}", server.lock);
+            let result =
+                connected_session(tx, session).and_then(|session| session.break_lock(&server));
+            send_lock_result(tx, &server, result);
+        }
+        QueryMessage::ListBackups(server) => {
+            let result =
+                connected_session(tx, session).and_then(|session| session.list_backups(&server));
+            send_backups(tx, &server, result);
+        }
+        QueryMessage::PruneBackups(server) => {
+            let result =
+                connected_session(tx, session).and_then(|session| session.prune_backups(&server));
+            send_backups(tx, &server, result);
+        }
+        QueryMessage::DownloadBackup { server, path } => {
+            let result = connected_session(tx, session)
+                .and_then(|session| session.download_backup(&server, &path));
+            let (document, error) = match result {
+                Ok(document) => (Some(document), None),
+                Err(error) => {
+                    log::error!("Can't download backup {path}: {error:?}");
+                    (None, Some(error.to_string()))
+                }
+            };
+
+            let _ = tx.send(ResponseMessage::BackupContent {
+                server,
+                path,
+                document,
+                error,
+            });
+        }
+    }
+}
+
+// The session of the worker's host, logged in again with the stored credentials if it died
+fn connected_session<'a>(
+    tx: &Sender<ResponseMessage>,
+    session: &'a mut Option<SSHWrapper>,
+) -> Result<&'a mut SSHWrapper, Box<dyn Error>> {
+    let session = session
+        .as_mut()
+        .ok_or("There is no session with the server, log in first")?;
+    if session.is_alive() {
+        return Ok(session);
     }
 
-    ControlFlow::Continue(())
+    let host = session.host().clone();
+    log::warn!("Session with {host} lost, reconnecting");
+    let _ = tx.send(ResponseMessage::ConnectionStatus {
+        host: host.clone(),
+        status: AuthStatus::Reconnecting,
+        error: None,
+    });
+
+    let error = match session.authenticate(report_hops(tx, &host)) {
+        Ok(true) => None,
+        Ok(false) => Some("Authentication failed".to_owned()),
+        Err(error) => Some(error.to_string()),
+    };
+    let _ = tx.send(ResponseMessage::ConnectionStatus {
+        host,
+        status: error.is_none().into(),
+        error: error.clone(),
+    });
+
+    match error {
+        None => Ok(session),
+        Some(error) => {
+            Err(format!("The connection was lost and reconnecting failed: {error}").into())
+        }
+    }
+}
+
+// Tell the frontend the progress of a login, only logins through jump hosts have hops worth showing
+fn report_hops<'a>(tx: &'a Sender<ResponseMessage>, host: &'a Host) -> impl Fn(&[HopStatus]) + 'a {
+    move |hops| {
+        if hops.len() > 1 {
+            let _ = tx.send(ResponseMessage::HopsStatus {
+                host: host.clone(),
+                hops: hops.to_vec(),
+            });
+        }
+    }
+}
+
+// Tell the frontend the new state of a lock, errors leave it unlocked
+fn send_lock_result(
+    tx: &Sender<ResponseMessage>,
+    server: &Server,
+    result: Result<LockStatus, Box<dyn Error>>,
+) {
+    let (lock, error) = match result {
+        Ok(lock) => (lock, None),
+        Err(error) => {
+            log::error!("Lock error on {server}: {error:?}");
+            (LockStatus::Unlocked, Some(error.to_string()))
+        }
+    };
+
+    let _ = tx.send(ResponseMessage::LockResult {
+        server: server.clone(),
+        lock,
+        error,
+    });
+}
+
+// Tell the frontend the backups of a map
+fn send_backups(
+    tx: &Sender<ResponseMessage>,
+    server: &Server,
+    result: Result<Vec<Backup>, Box<dyn Error>>,
+) {
+    let (backups, error) = match result {
+        Ok(backups) => (backups, None),
+        Err(error) => {
+            log::error!("Backups error on {server}: {error:?}");
+            (vec![], Some(error.to_string()))
+        }
+    };
+
+    let _ = tx.send(ResponseMessage::Backups {
+        server: server.clone(),
+        backups,
+        error,
+    });
 }
@@ -0,0 +1,282 @@
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+// Error produced when an alias or a destination has invalid syntax
+#[derive(Debug, Clone, PartialEq)]
+pub struct AddressError(pub String);
+
+impl Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for AddressError {}
+
+// The lookup key of a virtual alias entry
+#[derive(Clone, Debug, PartialEq)]
+pub enum Alias {
+    // A full address: user@example.com
+    Address { local: String, domain: String },
+    // Every address in a domain: @example.com
+    CatchAll(String),
+    // A local name without domain: user
+    Local(String),
+    // A bare domain, used to declare a virtual alias domain: example.com
+    Domain(String),
+}
+
+// A redirection target of a virtual alias entry
+#[derive(Clone, Debug, PartialEq)]
+pub enum Destination {
+    // A full address: user@example.com
+    Address { local: String, domain: String },
+    // A local mailbox: user
+    Local(String),
+}
+
+// Validate the part before the '@', either a dot-atom (john.doe+tag) or a quoted string ("john doe")
+fn validate_local(local: &str) -> Result<(), AddressError> {
+    if local.is_empty() {
+        return Err(AddressError("the local part is empty".to_owned()));
+    }
+
+    if local.len() > 64 {
+        return Err(AddressError(format!(
+            "the local part '{local}' is longer than 64 characters"
+        )));
+    }
+
+    if local.len() > 1 && local.starts_with('"') && local.ends_with('"') {
+        if local[1..local.len() - 1].contains(['"', '\r', '\n']) {
+            return Err(AddressError(format!("invalid quoted local part {local}")));
+        }
+
+        return Ok(());
+    }
+
+    if local.starts_with('.') || local.ends_with('.') || local.contains("..") {
+        return Err(AddressError(format!(
+            "the local part '{local}' has misplaced dots"
+        )));
+    }
+
+    match local
+        .chars()
+        .find(|x| !(x.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~.".contains(*x)))
+    {
+        Some(character) => Err(AddressError(format!(
+            "invalid character '{character}' in '{local}'"
+        ))),
+        None => Ok(()),
+    }
+}
+
+// Validate a domain name, made of letters, digits and hyphens separated by dots
+pub fn validate_domain(domain: &str) -> Result<(), AddressError> {
+    if domain.is_empty() {
+        return Err(AddressError("the domain is empty".to_owned()));
+    }
+
+    if domain.len() > 253 {
+        return Err(AddressError(format!(
+            "the domain '{domain}' is longer than 253 characters"
+        )));
+    }
+
+    for label in domain.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(AddressError(format!(
+                "the domain '{domain}' has an empty or too long label"
+            )));
+        }
+
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(AddressError(format!(
+                "the domain label '{label}' can't start or end with '-'"
+            )));
+        }
+
+        if let Some(character) = label
+            .chars()
+            .find(|x| !(x.is_ascii_alphanumeric() || *x == '-'))
+        {
+            return Err(AddressError(format!(
+                "invalid character '{character}' in domain '{domain}'"
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// A bare key looks like a domain if it has a dot and its last label isn't numeric
+fn looks_like_domain(text: &str) -> bool {
+    text.contains('.')
+        && text
+            .rsplit('.')
+            .next()
+            .is_some_and(|tld| tld.chars().any(|x| x.is_ascii_alphabetic()))
+        && validate_domain(text).is_ok()
+}
+
+// Split an address at the last '@' (a quoted local part may contain '@' too)
+fn split_address(text: &str) -> Result<(String, String), AddressError> {
+    let (local, domain) = text
+        .rsplit_once('@')
+        .ok_or_else(|| AddressError(format!("'{text}' is not an address")))?;
+
+    validate_local(local)?;
+    validate_domain(domain)?;
+
+    Ok((local.to_owned(), domain.to_owned()))
+}
+
+impl FromStr for Alias {
+    type Err = AddressError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+
+        if text.is_empty() {
+            return Err(AddressError("the alias is empty".to_owned()));
+        }
+
+        if let Some(domain) = text.strip_prefix('@') {
+            validate_domain(domain)?;
+            return Ok(Self::CatchAll(domain.to_owned()));
+        }
+
+        if text.contains('@') {
+            let (local, domain) = split_address(text)?;
+            return Ok(Self::Address { local, domain });
+        }
+
+        if looks_like_domain(text) {
+            return Ok(Self::Domain(text.to_owned()));
+        }
+
+        validate_local(text)?;
+        Ok(Self::Local(text.to_owned()))
+    }
+}
+
+impl FromStr for Destination {
+    type Err = AddressError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+
+        if text.is_empty() {
+            return Err(AddressError("the destination is empty".to_owned()));
+        }
+
+        if text.starts_with('@') {
+            return Err(AddressError(format!(
+                "'{text}' is a catch-all, it can't be used as a destination"
+            )));
+        }
+
+        if text.contains('@') {
+            let (local, domain) = split_address(text)?;
+            return Ok(Self::Address { local, domain });
+        }
+
+        validate_local(text)?;
+        Ok(Self::Local(text.to_owned()))
+    }
+}
+
+impl Display for Alias {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Address { local, domain } => write!(f, "{local}@{domain}"),
+            Self::CatchAll(domain) => write!(f, "@{domain}"),
+            Self::Local(local) => f.write_str(local),
+            Self::Domain(domain) => f.write_str(domain),
+        }
+    }
+}
+
+impl Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Address { local, domain } => write!(f, "{local}@{domain}"),
+            Self::Local(local) => f.write_str(local),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aliases_are_classified() {
+        assert_eq!(
+            "john.doe+tag@example.com".parse(),
+            Ok(Alias::Address {
+                local: "john.doe+tag".to_owned(),
+                domain: "example.com".to_owned(),
+            })
+        );
+        assert_eq!(
+            "@example.com".parse(),
+            Ok(Alias::CatchAll("example.com".to_owned()))
+        );
+        assert_eq!(
+            "example.com".parse(),
+            Ok(Alias::Domain("example.com".to_owned()))
+        );
+        assert_eq!(
+            "postmaster".parse(),
+            Ok(Alias::Local("postmaster".to_owned()))
+        );
+        assert_eq!(
+            "\"john@home\"@example.com".parse(),
+            Ok(Alias::Address {
+                local: "\"john@home\"".to_owned(),
+                domain: "example.com".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_addresses_are_rejected() {
+        for text in [
+            "",
+            "@",
+            "user@",
+            ".user@example.com",
+            "us..er@example.com",
+            "us er@example.com",
+            "user@-example.com",
+            "user@example..com",
+            "user@exa_mple.com",
+        ] {
+            assert!(text.parse::<Alias>().is_err(), "{text}");
+        }
+
+        let long = format!("{}@example.com", "a".repeat(65));
+        assert!(long.parse::<Alias>().is_err());
+    }
+
+    #[test]
+    fn catch_alls_are_not_destinations() {
+        assert!("@example.com".parse::<Destination>().is_err());
+        assert_eq!(
+            "mailbox".parse(),
+            Ok(Destination::Local("mailbox".to_owned()))
+        );
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for text in ["user@example.com", "@example.com", "example.com", "user"] {
+            assert_eq!(text.parse::<Alias>().unwrap().to_string(), text);
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::HashSet;
+
+use chrono::{Duration, NaiveDateTime};
+
+use super::mapdocument::MapDocument;
+
+// Format of the date in the name of the backups, as written by date +%Y-%m-%d_%H-%M-%S
+const DATE_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
+
+// A copy of a map made before uploading, named <map file name>_<date>.bak in the user's home
+#[derive(Clone, Debug, PartialEq)]
+pub struct Backup {
+    // Full path on the server
+    pub path: String,
+    // When it was made, in the server's local time
+    pub time: NaiveDateTime,
+}
+
+impl Backup {
+    // Read the list of backup paths, one per line, newest first. Files without a date right after the prefix belong to
+    // other maps whose name starts the same way
+    pub fn parse_listing(listing: &str, prefix: &str) -> Vec<Self> {
+        let mut backups: Vec<Self> = listing
+            .lines()
+            .map(|line| line.trim())
+            .filter_map(|path| {
+                let name = path.rsplit('/').next().unwrap_or(path);
+                let date = name
+                    .strip_prefix(prefix)?
+                    .strip_prefix('_')?
+                    .strip_suffix(".bak")?;
+
+                Some(Self {
+                    path: path.to_owned(),
+                    time: NaiveDateTime::parse_from_str(date, DATE_FORMAT).ok()?,
+                })
+            })
+            .collect();
+
+        backups.sort_by(|a, b| b.time.cmp(&a.time).then_with(|| b.path.cmp(&a.path)));
+        backups
+    }
+
+    // Name shown in the interface
+    pub fn label(&self) -> String {
+        self.time.format("%Y-%m-%d %H:%M:%S").to_string()
+    }
+}
+
+// Backups to delete: the ones after the newest keep, and the ones older than days
+pub fn expired(
+    backups: &[Backup],
+    keep: Option<usize>,
+    days: Option<u32>,
+    now: NaiveDateTime,
+) -> Vec<&Backup> {
+    backups
+        .iter()
+        .enumerate()
+        .filter(|(index, backup)| {
+            keep.is_some_and(|keep| *index >= keep)
+                || days.is_some_and(|days| now - backup.time > Duration::days(days as i64))
+        })
+        .map(|(_, backup)| backup)
+        .collect()
+}
+
+// How an entry differs between two versions of a map
+#[derive(Clone, Debug)]
+pub struct EntryDiff {
+    pub key: String,
+    // Values in the older version, None if the entry didn't exist
+    pub old: Option<Vec<String>>,
+    // Values in the newer version, None if the entry was removed
+    pub new: Option<Vec<String>>,
+}
+
+// Entries added, removed or changed from old to new, in the order they appear
+pub fn diff(old: &MapDocument, new: &MapDocument) -> Vec<EntryDiff> {
+    let mut keys: Vec<&str> = vec![];
+    let mut seen: HashSet<&str> = HashSet::new();
+    for entry in old.entries().chain(new.entries()) {
+        if seen.insert(entry.key()) {
+            keys.push(entry.key());
+        }
+    }
+
+    keys.into_iter()
+        .filter_map(|key| {
+            let (old, new) = (old.get(key), new.get(key));
+
+            (old != new).then(|| EntryDiff {
+                key: key.to_owned(),
+                old: old.map(|values| values.to_vec()),
+                new: new.map(|values| values.to_vec()),
+            })
+        })
+        .collect()
+}
+
+// A downloaded backup and what restoring it would undo, compared once instead of on every frame
+#[derive(Clone, Debug)]
+pub struct BackupView {
+    // Full path on the server
+    pub path: String,
+    pub document: MapDocument,
+    // From the backup to the map as it was last downloaded or saved
+    pub differences: Vec<EntryDiff>,
+}
+
+impl BackupView {
+    pub fn new(path: String, document: MapDocument, current: &MapDocument) -> Self {
+        let differences = diff(&document, current);
+
+        Self {
+            path,
+            document,
+            differences,
+        }
+    }
+
+    // Compare again after the map on the server changed
+    pub fn compare(&mut self, current: &MapDocument) {
+        self.differences = diff(&self.document, current);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::backend::mapdocument::Syntax;
+
+    fn time(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, DATE_FORMAT).unwrap()
+    }
+
+    fn document(data: &str) -> MapDocument {
+        MapDocument::parse(data, Syntax::default()).unwrap()
+    }
+
+    #[test]
+    fn listing_keeps_the_map_backups_newest_first() {
+        let listing = "\
+/home/op/virtual_2024-01-01_10-00-00.bak
+/home/op/virtual_2024-03-01_10-00-00.bak
+/home/op/virtualuser_2024-02-01_10-00-00.bak
+/home/op/virtual_notadate.bak
+/home/op/virtual_2024-02-01_10-00-00.bak.tmp
+
+  /home/op/virtual_2024-02-01_10-00-00.bak
+";
+        let backups = Backup::parse_listing(listing, "virtual");
+
+        let paths: Vec<&str> = backups.iter().map(|x| x.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "/home/op/virtual_2024-03-01_10-00-00.bak",
+                "/home/op/virtual_2024-02-01_10-00-00.bak",
+                "/home/op/virtual_2024-01-01_10-00-00.bak",
+            ]
+        );
+        assert_eq!(backups[0].label(), "2024-03-01 10:00:00");
+    }
+
+    #[test]
+    fn expired_backups_by_count_and_age() {
+        let backups: Vec<Backup> = ["2024-03-10", "2024-03-05", "2024-02-01", "2024-01-01"]
+            .into_iter()
+            .map(|date| Backup {
+                path: format!("virtual_{date}_00-00-00.bak"),
+                time: time(&format!("{date}_00-00-00")),
+            })
+            .collect();
+        let now = time("2024-03-11_00-00-00");
+        let dates = |expired: Vec<&Backup>| -> Vec<String> {
+            expired.iter().map(|x| x.label()[..10].to_owned()).collect()
+        };
+
+        assert!(expired(&backups, None, None, now).is_empty());
+        assert_eq!(
+            dates(expired(&backups, Some(2), None, now)),
+            ["2024-02-01", "2024-01-01"]
+        );
+        assert_eq!(
+            dates(expired(&backups, None, Some(30), now)),
+            ["2024-02-01", "2024-01-01"]
+        );
+        assert_eq!(
+            dates(expired(&backups, Some(3), Some(60), now)),
+            ["2024-01-01"]
+        );
+    }
+
+    #[test]
+    fn view_lists_what_restoring_undoes() {
+        let current = document("a x\nb y2\nd w\n");
+        let mut view = BackupView::new("backup".to_owned(), document("a x\nb y\nc z\n"), &current);
+
+        let changes: Vec<(&str, bool, bool)> = view
+            .differences
+            .iter()
+            .map(|x| (x.key.as_str(), x.old.is_some(), x.new.is_some()))
+            .collect();
+        assert_eq!(
+            changes,
+            [("b", true, true), ("c", true, false), ("d", false, true)]
+        );
+
+        view.compare(&document("a x\nb y\nc z\n"));
+        assert!(view.differences.is_empty());
+    }
+}
@@ -1,6 +1,9 @@
+use std::{error::Error, io::ErrorKind, path::Path};
+
 use serde::Deserialize;
+use toml_edit::{value, Array, Document, InlineTable, Item, Table, Value};
 
-use super::server::Server;
+use super::{server::Server, sshconfig::SshConfig};
 
 // Store the app configuration (to be loaded from TOML, JSON, etc...)
 #[derive(Deserialize)]
@@ -10,3 +13,192 @@ pub struct Configuration {
     // The server list
     pub servers: Vec<Server>,
 }
+
+// Add a server for every Host entry of the ssh config matching pattern to the configuration file, keeping the rest of it
+// as it is. The servers use the alias as address so the ssh config keeps describing them. Returns the aliases added,
+// the ones already listed with the same config_path are skipped
+pub fn import_ssh_hosts(
+    file: &Path,
+    ssh_config: &SshConfig,
+    pattern: &str,
+    config_path: &str,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let text = match std::fs::read_to_string(file) {
+        Ok(text) => text,
+        Err(error) if error.kind() == ErrorKind::NotFound => String::new(),
+        Err(error) => return Err(error.into()),
+    };
+    let mut document: Document = text.parse()?;
+
+    if !document.contains_key("username") {
+        let username = std::env::var("USER")
+            .or_else(|_| std::env::var("USERNAME"))
+            .unwrap_or_default();
+        document["username"] = value(username);
+    }
+
+    let listed = |addr: Option<&str>, path: Option<&str>| {
+        (addr.map(str::to_owned), path == Some(config_path))
+    };
+
+    let mut added = vec![];
+    match document.entry("servers").or_insert(value(Array::new())) {
+        // [[servers]] tables
+        Item::ArrayOfTables(tables) => {
+            let existing: Vec<_> = tables
+                .iter()
+                .map(|table| {
+                    listed(
+                        table.get("addr").and_then(Item::as_str),
+                        table.get("config_path").and_then(Item::as_str),
+                    )
+                })
+                .collect();
+
+            for alias in ssh_config.aliases(pattern) {
+                if !existing.contains(&(Some(alias.clone()), true)) {
+                    let mut table = Table::new();
+                    table["addr"] = value(&alias);
+                    table["config_path"] = value(config_path);
+                    tables.push(table);
+                    added.push(alias);
+                }
+            }
+        }
+        // servers = [ { ... }, ... ]
+        item => {
+            let array = item
+                .as_array_mut()
+                .ok_or("servers must be an array in the configuration")?;
+            let existing: Vec<_> = array
+                .iter()
+                .map(|server| {
+                    let table = server.as_inline_table();
+                    listed(
+                        table.and_then(|table| table.get("addr")?.as_str()),
+                        table.and_then(|table| table.get("config_path")?.as_str()),
+                    )
+                })
+                .collect();
+
+            // One server per line, like the examples. What followed the last server (a comment) stays after it
+            let trailing = array.trailing().as_str().unwrap_or_default().to_owned();
+            let mut prefix = if trailing.ends_with('\n') {
+                format!("{trailing}    ")
+            } else {
+                format!("{trailing}\n    ")
+            };
+
+            for alias in ssh_config.aliases(pattern) {
+                if !existing.contains(&(Some(alias.clone()), true)) {
+                    let mut table = InlineTable::new();
+                    table.insert("addr", alias.as_str().into());
+                    table.insert("config_path", config_path.into());
+                    array.push_formatted(Value::InlineTable(table).decorated(prefix, ""));
+                    prefix = "\n    ".to_owned();
+                    added.push(alias);
+                }
+            }
+
+            if !added.is_empty() {
+                array.set_trailing_comma(true);
+                array.set_trailing("\n");
+            }
+        }
+    }
+
+    if !added.is_empty() {
+        std::fs::write(file, document.to_string())?;
+    }
+
+    Ok(added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SSH_CONFIG: &str = "\
+Host mail-1 mail-2
+    User admin
+
+Host backup-1
+    Port 2222
+
+Host mail-*
+    Port 22
+";
+
+    // A configuration file of its own for every test, they run concurrently
+    fn import(name: &str, text: Option<&str>, pattern: &str) -> (Vec<String>, String) {
+        let file = std::env::temp_dir().join(format!(
+            "email-forward-manager-{}-{name}.toml",
+            std::process::id()
+        ));
+        match text {
+            Some(text) => std::fs::write(&file, text).unwrap(),
+            None => {
+                let _ = std::fs::remove_file(&file);
+            }
+        }
+
+        let added = import_ssh_hosts(
+            &file,
+            &SshConfig::parse(SSH_CONFIG),
+            pattern,
+            "/etc/postfix/virtual",
+        )
+        .unwrap();
+        let text = std::fs::read_to_string(&file).unwrap_or_default();
+        let _ = std::fs::remove_file(&file);
+
+        (added, text)
+    }
+
+    #[test]
+    fn a_missing_file_is_created() {
+        let (added, text) = import("missing", None, "*");
+
+        assert_eq!(added, ["mail-1", "mail-2", "backup-1"]);
+        let document: Document = text.parse().unwrap();
+        assert!(document.contains_key("username"));
+        assert_eq!(document["servers"].as_array().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn comments_and_servers_survive() {
+        let (added, text) = import(
+            "inline",
+            Some(
+                "# operators\nusername = \"jdoe\"\nservers = [\n    { addr = \"mx\", config_path = \"/etc/postfix/virtual\" }, # main\n]\n",
+            ),
+            "mail-*",
+        );
+
+        assert_eq!(added, ["mail-1", "mail-2"]);
+        assert_eq!(
+            text,
+            "# operators\nusername = \"jdoe\"\nservers = [\n    { addr = \"mx\", config_path = \"/etc/postfix/virtual\" }, # main\n    \
+            { addr = \"mail-1\", config_path = \"/etc/postfix/virtual\" },\n    \
+            { addr = \"mail-2\", config_path = \"/etc/postfix/virtual\" },\n]\n"
+        );
+    }
+
+    #[test]
+    fn listed_hosts_are_skipped() {
+        let tables = "username = \"jdoe\"\n\n# the relays\n[[servers]]\naddr = \"mail-1\"\nconfig_path = \"/etc/postfix/virtual\"\n\n\
+            [[servers]]\naddr = \"mail-2\"\nconfig_path = \"/etc/postfix/aliases\"\n";
+        let (added, text) = import("tables", Some(tables), "mail-?");
+
+        // mail-2 is only listed with another map
+        assert_eq!(added, ["mail-2"]);
+        assert!(text.starts_with(tables));
+        assert!(text
+            .ends_with("[[servers]]\naddr = \"mail-2\"\nconfig_path = \"/etc/postfix/virtual\"\n"));
+
+        // Nothing new, the file isn't touched
+        let (added, text) = import("unchanged", Some(&text), "mail-?");
+        assert!(added.is_empty());
+        assert_eq!(text.matches("[[servers]]").count(), 3);
+    }
+}
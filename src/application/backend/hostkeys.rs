@@ -0,0 +1,204 @@
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use base64::{
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD},
+    Engine,
+};
+use ssh2::{CheckResult, HashType, HostKeyType, KnownHostFileKind, Session};
+
+// Host keys accepted from the application, stored next to config.toml in the same format as ~/.ssh/known_hosts
+const APP_KNOWN_HOSTS: &str = "known_hosts";
+
+// The key a server presented during the handshake
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostKey {
+    pub addr: String,
+    pub port: u16,
+    // Algorithm name, as written in known_hosts (ssh-ed25519, ecdsa-sha2-nistp256...)
+    pub algorithm: String,
+    // The public key, base64 encoded
+    pub key: String,
+    // SHA256 fingerprint, as shown by ssh-keygen -l
+    pub fingerprint: String,
+}
+
+impl HostKey {
+    // Name of the host in known_hosts, with the port only when it's not the default one
+    fn host_name(&self) -> String {
+        if self.port == 22 {
+            self.addr.clone()
+        } else {
+            format!("[{}]:{}", self.addr, self.port)
+        }
+    }
+}
+
+// Why the connection with a server can't be trusted
+#[derive(Debug)]
+pub enum HostKeyError {
+    // The host isn't in any known_hosts file, the user must confirm the key
+    Unknown(HostKey),
+    // The host is known with another key: the server was reinstalled, or someone is intercepting the connection
+    Changed(HostKey),
+}
+
+impl Display for HostKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown(key) => write!(
+                f,
+                "Unknown host key for {}: {} {}",
+                key.host_name(),
+                key.algorithm,
+                key.fingerprint
+            ),
+            Self::Changed(key) => write!(
+                f,
+                "The host key for {} has changed, it is now {} {}. Someone could be intercepting the connection. \
+                If the key was changed on purpose, remove the old key from ~/.ssh/known_hosts and {APP_KNOWN_HOSTS}",
+                key.host_name(),
+                key.algorithm,
+                key.fingerprint
+            ),
+        }
+    }
+}
+
+impl Error for HostKeyError {}
+
+// Files checked for known keys: the user's OpenSSH file and the one managed by the application
+fn known_hosts_files() -> Vec<PathBuf> {
+    let mut files = vec![PathBuf::from(APP_KNOWN_HOSTS)];
+
+    if let Some(home) = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")) {
+        files.push(Path::new(&home).join(".ssh").join("known_hosts"));
+    }
+
+    files
+}
+
+// Check the key presented by the server, must be called after the handshake and before sending any credential
+pub fn verify(session: &Session, addr: &str, port: u16) -> Result<(), Box<dyn Error>> {
+    let (key, kind) = session
+        .host_key()
+        .ok_or_else(|| std::io::Error::other("The server didn't present a host key"))?;
+
+    let algorithm = match kind {
+        HostKeyType::Rsa => "ssh-rsa",
+        HostKeyType::Dss => "ssh-dss",
+        HostKeyType::Ecdsa256 => "ecdsa-sha2-nistp256",
+        HostKeyType::Ecdsa384 => "ecdsa-sha2-nistp384",
+        HostKeyType::Ecdsa521 => "ecdsa-sha2-nistp521",
+        HostKeyType::Ed25519 => "ssh-ed25519",
+        HostKeyType::Unknown => "unknown",
+    };
+    let fingerprint = session
+        .host_key_hash(HashType::Sha256)
+        .map(|hash| format!("SHA256:{}", STANDARD_NO_PAD.encode(hash)))
+        .unwrap_or_default();
+
+    let host_key = HostKey {
+        addr: addr.to_owned(),
+        port,
+        algorithm: algorithm.to_owned(),
+        key: STANDARD.encode(key),
+        fingerprint,
+    };
+
+    // A mismatch in any file is an error, even if another file has the new key
+    let mut found = false;
+    for file in known_hosts_files().iter().filter(|file| file.exists()) {
+        let mut known_hosts = session.known_hosts()?;
+        if let Err(error) = known_hosts.read_file(file, KnownHostFileKind::OpenSSH) {
+            log::warn!("Can't read {}: {error}", file.display());
+            continue;
+        }
+
+        match known_hosts.check_port(addr, port, key) {
+            CheckResult::Match => found = true,
+            CheckResult::Mismatch => return Err(Box::new(HostKeyError::Changed(host_key))),
+            CheckResult::NotFound => {}
+            CheckResult::Failure => {
+                log::warn!("Can't check the host key of {addr} in {}", file.display())
+            }
+        }
+    }
+
+    if found {
+        log::trace!(
+            "Host key for {addr}:{port} verified: {}",
+            host_key.fingerprint
+        );
+        Ok(())
+    } else {
+        Err(Box::new(HostKeyError::Unknown(host_key)))
+    }
+}
+
+// Algorithms of the keys known for a host, so the handshake asks for one of them. Otherwise the server could present
+// a key of another type, which isn't known and would have to be confirmed again
+pub fn known_algorithms(session: &Session, addr: &str, port: u16) -> Vec<String> {
+    let mut algorithms: Vec<String> = vec![];
+
+    for file in known_hosts_files().iter().filter(|file| file.exists()) {
+        let text = match std::fs::read_to_string(file) {
+            Ok(text) => text,
+            Err(error) => {
+                log::warn!("Can't read {}: {error}", file.display());
+                continue;
+            }
+        };
+
+        // Every line is checked on its own with a key that can't be the host's: a mismatch means the line is about
+        // the host. libssh2 matches the names, hashed ones included, the same way verify does
+        for line in text.lines() {
+            let line = line.trim();
+            let algorithm = match line.split_whitespace().nth(1) {
+                Some(algorithm) if !line.starts_with(['#', '@']) => algorithm,
+                _ => continue,
+            };
+
+            let Ok(mut known_hosts) = session.known_hosts() else {
+                continue;
+            };
+            if known_hosts
+                .read_str(line, KnownHostFileKind::OpenSSH)
+                .is_ok()
+                && matches!(
+                    known_hosts.check_port(addr, port, b"-"),
+                    CheckResult::Mismatch
+                )
+                && !algorithms.iter().any(|x| x == algorithm)
+            {
+                algorithms.push(algorithm.to_owned());
+            }
+        }
+    }
+
+    algorithms
+}
+
+// Store a key the user accepted in the application's known_hosts
+pub fn trust(host_key: &HostKey) -> Result<(), Box<dyn Error>> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(APP_KNOWN_HOSTS)?;
+
+    // A single appending write, the workers of several servers may trust keys at the same time
+    let line = format!(
+        "{} {} {}\n",
+        host_key.host_name(),
+        host_key.algorithm,
+        host_key.key
+    );
+    file.write_all(line.as_bytes())?;
+
+    Ok(())
+}
@@ -0,0 +1,133 @@
+use std::fmt::{self, Display};
+
+// Who holds the lock of a map, written in the lock file as key=value lines
+#[derive(Clone, Debug, PartialEq)]
+pub struct LockOwner {
+    // Local user running the application
+    pub operator: String,
+    // Local machine running the application
+    pub host: String,
+    // Process of the application, the same operator may run it twice
+    pub pid: u32,
+    // When the lock was taken, in the operator's local time
+    pub since: String,
+}
+
+impl LockOwner {
+    // An owner for this process, taking the lock now
+    pub fn current() -> Self {
+        let operator = std::env::var("USER")
+            .or_else(|_| std::env::var("USERNAME"))
+            .unwrap_or("unknown".to_owned());
+        let host = std::env::var("HOSTNAME")
+            .or_else(|_| std::env::var("COMPUTERNAME"))
+            .or_else(|_| {
+                std::fs::read_to_string("/etc/hostname").map(|name| name.trim().to_owned())
+            })
+            .unwrap_or("unknown".to_owned());
+
+        Self {
+            operator,
+            host,
+            pid: std::process::id(),
+            since: chrono::Local::now()
+                .format("%Y-%m-%d %H:%M:%S %z")
+                .to_string(),
+        }
+    }
+
+    // Read the contents of a lock file
+    pub fn parse(data: &str) -> Option<Self> {
+        let field = |name: &str| {
+            data.lines()
+                .find_map(|line| line.trim().strip_prefix(name)?.strip_prefix('='))
+                .map(|value| value.to_owned())
+        };
+
+        Some(Self {
+            operator: field("operator")?,
+            host: field("host")?,
+            pid: field("pid")?.parse().ok()?,
+            since: field("since")?,
+        })
+    }
+
+    // Contents of the lock file, without the final line terminator so it survives the shell's $(...)
+    pub fn render(&self) -> String {
+        format!(
+            "operator={}\nhost={}\npid={}\nsince={}",
+            self.operator, self.host, self.pid, self.since
+        )
+    }
+
+    // Whether the lock was taken by this same process
+    pub fn is_current_process(&self) -> bool {
+        let current = Self::current();
+        self.operator == current.operator && self.host == current.host && self.pid == current.pid
+    }
+}
+
+impl Display for LockOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.operator, self.host)
+    }
+}
+
+// State of the advisory lock of a map
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum LockStatus {
+    // Nobody is editing the map from this application
+    #[default]
+    Unlocked,
+    // The map was edited and the lock was asked for
+    Requested,
+    // This application holds the lock
+    Ours(LockOwner),
+    // Someone else is editing the map
+    Theirs(LockOwner),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn owners_round_trip() {
+        let owner = LockOwner {
+            operator: "ana".to_owned(),
+            host: "desk-1".to_owned(),
+            pid: 4242,
+            since: "2024-05-01 10:00:00 +0200".to_owned(),
+        };
+
+        assert_eq!(LockOwner::parse(&owner.render()), Some(owner.clone()));
+        // The shell may add a line terminator and indent it
+        assert_eq!(
+            LockOwner::parse(&format!("  {}\n", owner.render().replace('\n', "\r\n  "))),
+            Some(owner.clone())
+        );
+        assert_eq!(owner.to_string(), "ana@desk-1");
+    }
+
+    #[test]
+    fn incomplete_lock_files_are_rejected() {
+        assert_eq!(LockOwner::parse(""), None);
+        assert_eq!(
+            LockOwner::parse("operator=ana\nhost=desk-1\nsince=now"),
+            None
+        );
+        assert_eq!(
+            LockOwner::parse("operator=ana\nhost=desk-1\npid=abc\nsince=now"),
+            None
+        );
+    }
+
+    #[test]
+    fn the_current_owner_is_this_process() {
+        assert!(LockOwner::current().is_current_process());
+
+        let mut other = LockOwner::current();
+        other.pid += 1;
+        assert!(!other.is_current_process());
+    }
+}
@@ -0,0 +1,853 @@
+use std::{
+    error::Error,
+    fmt::{self, Display},
+};
+
+// Error produced when a map file can't be parsed
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    // Line number (1 based) where the problem was found
+    pub line: usize,
+    // Human readable description of the problem
+    pub message: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for ParseError {}
+
+// Lexical rules of a lookup table, every map format provides its own
+#[derive(Clone, Copy, Debug)]
+pub struct Syntax {
+    // Character that ends the key besides whitespace (':' in aliases(5))
+    pub key_terminator: Option<char>,
+    // The value is a list split on commas and whitespace, instead of a single value
+    pub list_values: bool,
+    // Text between the key and the value for entries created from scratch
+    pub separator: &'static str,
+    // Text between values for entries created from scratch
+    pub value_separator: &'static str,
+    // Keys are delimited patterns that may contain whitespace (/^(.*)@example\.com$/), and
+    // `if`/`endif` lines have no value, as in regexp_table(5) and pcre_table(5)
+    pub pattern_keys: bool,
+}
+
+// postmap(1) input syntax: `key whitespace value`
+impl Default for Syntax {
+    fn default() -> Self {
+        Self {
+            key_terminator: None,
+            list_values: true,
+            separator: " ",
+            value_separator: " ",
+            pattern_keys: false,
+        }
+    }
+}
+
+// A single `key value, value...` entry of the map
+#[derive(Clone, Debug)]
+pub struct MapEntry {
+    // The lookup key (tipically an email address)
+    key: String,
+    // The values for the key (tipically the redirection targets)
+    values: Vec<String>,
+    // Text found between the key and the first value, reused when the entry is rendered again
+    separator: String,
+    // Text placed between values when the entry is rendered again
+    value_separator: String,
+    // Line terminator of the entry ("\n", "\r\n" or "" for the last line of the file)
+    ending: String,
+    // Original text of the entry, only present while the entry is untouched
+    raw: Option<String>,
+    // Comments and blank lines found between the continuation lines of the entry
+    interleaved: Vec<String>,
+}
+
+impl MapEntry {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn values(&self) -> &[String] {
+        &self.values
+    }
+
+    fn set_values(&mut self, values: Vec<String>) {
+        if self.values != values {
+            self.values = values;
+            self.raw = None;
+        }
+    }
+
+    fn set_key(&mut self, key: &str) {
+        if self.key != key {
+            self.key = key.to_owned();
+            self.raw = None;
+        }
+    }
+
+    // Render the entry, returning the original text if it wasn't modified
+    fn render(&self) -> String {
+        match &self.raw {
+            Some(raw) => raw.clone(),
+            // Comments found between continuation lines are kept right before the rendered entry.
+            // Lines without values (endif) don't get a separator
+            None => format!(
+                "{}{}{}{}{}",
+                self.interleaved.concat(),
+                self.key,
+                if self.values.is_empty() {
+                    ""
+                } else {
+                    &self.separator
+                },
+                self.values.join(&self.value_separator),
+                self.ending
+            ),
+        }
+    }
+}
+
+// A logical line being assembled from its physical lines
+struct PendingEntry {
+    // Line number where the logical line starts
+    line: usize,
+    // The logical line, without line terminators
+    content: String,
+    // Every physical line that is part of the logical line
+    raw: String,
+    // Comments and blank lines found between the continuation lines
+    interleaved: Vec<String>,
+    // Line terminator of the last physical line
+    ending: String,
+}
+
+// A complete logical line
+enum Finished {
+    Entry(MapEntry),
+    // A line postmap(1) warns about and skips, like a key without value. It's kept in the file as it was
+    Ignored { raw: String, warning: ParseError },
+}
+
+impl PendingEntry {
+    // Split the logical line into key and values
+    fn finish(self, syntax: &Syntax) -> Result<Finished, ParseError> {
+        let error = |message: String| ParseError {
+            line: self.line,
+            message,
+        };
+        let format_error = || match syntax.key_terminator {
+            Some(terminator) => error(format!(
+                "expected format: key{terminator} value, got '{}'",
+                self.content
+            )),
+            None => error(format!(
+                "expected format: key whitespace value, got '{}'",
+                self.content
+            )),
+        };
+
+        let ignored = || {
+            Ok(Finished::Ignored {
+                raw: self.raw.clone(),
+                warning: format_error(),
+            })
+        };
+
+        // The key ends at the first whitespace (space or tab), or at the terminator if the format has one
+        let (key, rest) = match syntax.key_terminator {
+            _ if syntax.pattern_keys => {
+                let (_, key_end) = split_pattern_key(&self.content).map_err(error)?;
+                self.content.split_at(key_end)
+            }
+            Some(terminator) => {
+                let Some(key_end) = self.content.find(terminator) else {
+                    return ignored();
+                };
+                let key = self.content[..key_end].trim_end();
+                if key.contains(char::is_whitespace) {
+                    return ignored();
+                }
+                (key, &self.content[key.len()..])
+            }
+            None => match self.content.find(char::is_whitespace) {
+                Some(key_end) => self.content.split_at(key_end),
+                None => return ignored(),
+            },
+        };
+
+        let value = rest
+            .trim_start()
+            .strip_prefix(|x| Some(x) == syntax.key_terminator)
+            .unwrap_or(rest.trim_start())
+            .trim_start();
+        // Pattern tables have lines without value (if, endif), the format decides when one is missing
+        if key.is_empty() || (value.is_empty() && !syntax.pattern_keys) {
+            return ignored();
+        }
+
+        // Keep the text between the key and the values so the entry can be rendered in the same style
+        let separator = rest[..rest.len() - value.len()].to_owned();
+        let values = if syntax.list_values {
+            split_values(value).map_err(error)?
+        } else if value.is_empty() {
+            vec![]
+        } else {
+            vec![value.trim_end().to_owned()]
+        };
+
+        Ok(Finished::Entry(MapEntry {
+            key: key.to_owned(),
+            separator,
+            // Entries with a single value don't show a preference, so they use the default
+            value_separator: if value.contains(',') {
+                ", "
+            } else if values.len() > 1 {
+                " "
+            } else {
+                syntax.value_separator
+            }
+            .to_owned(),
+            values,
+            ending: self.ending,
+            raw: Some(self.raw),
+            interleaved: self.interleaved,
+        }))
+    }
+}
+
+// The key of a pattern table line, as regexp_table(5) and pcre_table(5) split it
+pub enum PatternKey<'a> {
+    Endif,
+    // `[if ][!]/pattern/flags`, with any delimiter instead of '/'
+    Pattern {
+        condition: bool,
+        negated: bool,
+        delimiter: char,
+        // Between the delimiters, escaped delimiters included
+        body: &'a str,
+        flags: &'a str,
+    },
+}
+
+// Split the key at the start of a pattern table line, returning it with the length of its text
+pub fn split_pattern_key(content: &str) -> Result<(PatternKey<'_>, usize), String> {
+    let word_end = content.find(char::is_whitespace).unwrap_or(content.len());
+    if &content[..word_end] == "endif" {
+        return Ok((PatternKey::Endif, word_end));
+    }
+
+    // Skip the `if` and `!` prefixes
+    let mut start = 0;
+    let condition = &content[..word_end] == "if";
+    if condition {
+        start = word_end + (content[word_end..].len() - content[word_end..].trim_start().len());
+    }
+    let negated = content[start..].starts_with('!');
+    if negated {
+        start += 1;
+    }
+
+    let mut characters = content[start..].char_indices();
+    let delimiter = match characters.next() {
+        Some((_, delimiter)) if !delimiter.is_whitespace() && !delimiter.is_alphanumeric() => {
+            delimiter
+        }
+        _ => return Err(format!("expected format: /pattern/ value, got '{content}'")),
+    };
+
+    // The pattern ends at the next delimiter that isn't escaped with a backslash
+    let mut escaped = false;
+    let end = characters
+        .find(|(_, character)| {
+            let found = !escaped && *character == delimiter;
+            escaped = !escaped && *character == '\\';
+            found
+        })
+        .map(|(index, _)| start + index)
+        .ok_or_else(|| format!("unterminated pattern in '{content}'"))?;
+    let body = &content[start + delimiter.len_utf8()..end];
+
+    // Flags follow the closing delimiter
+    let flags_start = end + delimiter.len_utf8();
+    let flags_end = content[flags_start..]
+        .find(|x: char| !x.is_ascii_alphabetic())
+        .map(|index| flags_start + index)
+        .unwrap_or(content.len());
+
+    Ok((
+        PatternKey::Pattern {
+            condition,
+            negated,
+            delimiter,
+            body,
+            flags: &content[flags_start..flags_end],
+        },
+        flags_end,
+    ))
+}
+
+// Split a value on commas and whitespace, without breaking double quoted text ("like this"@example.com)
+fn split_values(value: &str) -> Result<Vec<String>, String> {
+    let mut values = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+
+    for character in value.chars() {
+        match character {
+            '"' => {
+                quoted = !quoted;
+                current.push(character);
+            }
+            ',' | ' ' | '\t' if !quoted => {
+                if !current.is_empty() {
+                    values.push(std::mem::take(&mut current));
+                }
+            }
+            _ => current.push(character),
+        }
+    }
+
+    if quoted {
+        return Err(format!("unbalanced double quote in '{value}'"));
+    }
+
+    if !current.is_empty() {
+        values.push(current);
+    }
+
+    Ok(values)
+}
+
+// The line for a complete logical line, ignored lines are kept verbatim and their warning collected
+fn finish_line(finished: Finished, ignored: &mut Vec<ParseError>) -> MapLine {
+    match finished {
+        Finished::Entry(entry) => MapLine::Entry(entry),
+        Finished::Ignored { raw, warning } => {
+            ignored.push(warning);
+            MapLine::Verbatim(raw)
+        }
+    }
+}
+
+// A physical line in the document
+#[derive(Clone, Debug)]
+enum MapLine {
+    // Comments and blank lines, kept byte-for-byte
+    Verbatim(String),
+    // Actual map entries
+    Entry(MapEntry),
+}
+
+// Lossless representation of a postfix lookup table, unchanged lines are rendered exactly as they were read
+#[derive(Clone, Debug, Default)]
+pub struct MapDocument {
+    lines: Vec<MapLine>,
+    // Lines postfix ignores, with the warning postmap gives for them
+    ignored: Vec<ParseError>,
+    // Rules used to parse the document and to render new entries
+    syntax: Syntax,
+}
+
+impl MapDocument {
+    // Parse the contents of a map file
+    //
+    // A logical line starts with non-whitespace text, and a line that starts with whitespace continues it.
+    // Blank lines and lines whose first non-whitespace character is '#' are comments, even between continuation lines
+    pub fn parse(data: &str, syntax: Syntax) -> Result<Self, ParseError> {
+        let mut lines = vec![];
+        // The logical line being assembled
+        let mut current: Option<PendingEntry> = None;
+        // Comments after the current logical line, they are part of it only if a continuation line follows
+        let mut comments: Vec<String> = vec![];
+        let mut ignored = vec![];
+
+        for (index, line) in data.split_inclusive('\n').enumerate() {
+            let content = line.trim_end_matches(['\r', '\n']);
+            let ending = &line[content.len()..];
+
+            // Comments and blank lines are stored as they are
+            if content.trim().is_empty() || content.trim_start().starts_with('#') {
+                if current.is_some() {
+                    comments.push(line.to_owned());
+                } else {
+                    lines.push(MapLine::Verbatim(line.to_owned()));
+                }
+                continue;
+            }
+
+            // Continuation lines are appended to the current logical line
+            if content.starts_with(char::is_whitespace) {
+                let Some(entry) = current.as_mut() else {
+                    return Err(ParseError {
+                        line: index + 1,
+                        message: "logical line must not start with whitespace".to_owned(),
+                    });
+                };
+
+                for comment in comments.drain(..) {
+                    entry.raw += &comment;
+                    entry.interleaved.push(comment);
+                }
+                entry.content += content;
+                entry.raw += line;
+                entry.ending = ending.to_owned();
+                continue;
+            }
+
+            // A new logical line starts here, so the previous one is complete
+            if let Some(entry) = current.take() {
+                lines.push(finish_line(entry.finish(&syntax)?, &mut ignored));
+            }
+            lines.extend(comments.drain(..).map(MapLine::Verbatim));
+
+            current = Some(PendingEntry {
+                line: index + 1,
+                content: content.to_owned(),
+                raw: line.to_owned(),
+                interleaved: vec![],
+                ending: ending.to_owned(),
+            });
+        }
+
+        if let Some(entry) = current.take() {
+            lines.push(finish_line(entry.finish(&syntax)?, &mut ignored));
+        }
+        lines.extend(comments.drain(..).map(MapLine::Verbatim));
+
+        Ok(Self {
+            lines,
+            ignored,
+            syntax,
+        })
+    }
+
+    // Iterate over the entries in file order
+    pub fn entries(&self) -> impl Iterator<Item = &MapEntry> {
+        self.lines.iter().filter_map(|line| match line {
+            MapLine::Entry(entry) => Some(entry),
+            MapLine::Verbatim(_) => None,
+        })
+    }
+
+    fn entry_mut(&mut self, key: &str) -> Option<&mut MapEntry> {
+        self.lines.iter_mut().find_map(|line| match line {
+            MapLine::Entry(entry) if entry.key == key => Some(entry),
+            _ => None,
+        })
+    }
+
+    // Values of the first entry for a key
+    pub fn get(&self, key: &str) -> Option<&[String]> {
+        self.entries()
+            .find(|entry| entry.key == key)
+            .map(|entry| entry.values.as_slice())
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.entries().any(|entry| entry.key == key)
+    }
+
+    // The entry at a position, counting entries only. Ordered tables repeat keys (endif), so their entries are
+    // addressed by position instead of by key
+    fn entry_at_mut(&mut self, index: usize) -> Option<&mut MapEntry> {
+        self.lines
+            .iter_mut()
+            .filter_map(|line| match line {
+                MapLine::Entry(entry) => Some(entry),
+                MapLine::Verbatim(_) => None,
+            })
+            .nth(index)
+    }
+
+    // Update the values for a key, appending a new entry at the end of the file if the key isn't there yet
+    pub fn set(&mut self, key: &str, values: Vec<String>) {
+        match self.entry_mut(key) {
+            Some(entry) => entry.set_values(values),
+            None => self.push(key, values),
+        }
+    }
+
+    // Update the values of the entry at a position
+    pub fn set_at(&mut self, index: usize, values: Vec<String>) {
+        if let Some(entry) = self.entry_at_mut(index) {
+            entry.set_values(values);
+        }
+    }
+
+    // Append a new entry at the end of the file, even if the key is already there
+    pub fn push(&mut self, key: &str, values: Vec<String>) {
+        // The last line of the file may lack a line terminator, which must be added before appending
+        self.terminate_last_line();
+
+        // New entries follow the style of the last entry in the file
+        let (separator, value_separator) = self
+            .entries()
+            .last()
+            .map(|entry| (entry.separator.clone(), entry.value_separator.clone()))
+            .unwrap_or((
+                self.syntax.separator.to_owned(),
+                self.syntax.value_separator.to_owned(),
+            ));
+
+        self.lines.push(MapLine::Entry(MapEntry {
+            key: key.to_owned(),
+            values,
+            separator,
+            value_separator,
+            ending: "\n".to_owned(),
+            raw: None,
+            interleaved: vec![],
+        }));
+    }
+
+    // Add a line terminator to the last line of the file if it lacks one
+    fn terminate_last_line(&mut self) {
+        match self.lines.last_mut() {
+            Some(MapLine::Verbatim(line)) if !line.ends_with('\n') => line.push('\n'),
+            Some(MapLine::Entry(entry)) if entry.ending.is_empty() => {
+                entry.raw = entry.raw.take().map(|raw| raw + "\n");
+                entry.ending = "\n".to_owned();
+            }
+            _ => {}
+        }
+    }
+
+    // Change the key of the entry at a position, keeping its position in the file
+    pub fn rename_at(&mut self, index: usize, new_key: &str) {
+        if let Some(entry) = self.entry_at_mut(index) {
+            entry.set_key(new_key);
+        }
+    }
+
+    // Move the entry at a position (counting entries only) to the place of another entry, comments and blank lines stay where they are
+    pub fn move_entry(&mut self, from: usize, to: usize) {
+        let positions: Vec<usize> = self
+            .lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| matches!(line, MapLine::Entry(_)))
+            .map(|(index, _)| index)
+            .collect();
+        if from >= positions.len() || to >= positions.len() || from == to {
+            return;
+        }
+
+        // The entry may be moved away from the end of the file, where it may lack a line terminator
+        self.terminate_last_line();
+
+        let entry = self.lines.remove(positions[from]);
+        self.lines.insert(positions[to], entry);
+    }
+
+    // Remove the entries for a key
+    pub fn remove(&mut self, key: &str) {
+        self.lines
+            .retain(|line| !matches!(line, MapLine::Entry(entry) if entry.key == key));
+    }
+
+    // Remove the entry at a position, comments and blank lines around it stay
+    pub fn remove_at(&mut self, index: usize) {
+        if let Some(position) = self
+            .lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| matches!(line, MapLine::Entry(_)))
+            .map(|(position, _)| position)
+            .nth(index)
+        {
+            self.lines.remove(position);
+        }
+    }
+
+    // Find duplicated keys, keys that only differ by case and repeated destinations
+    pub fn normalization_report(&self) -> NormalizationReport {
+        let mut report = NormalizationReport {
+            ignored_lines: self.ignored.clone(),
+            ..Default::default()
+        };
+        // Keys grouped by their lowercase form, in file order
+        let mut groups: Vec<(String, Vec<&str>)> = vec![];
+
+        for entry in self.entries() {
+            let folded = entry.key.to_lowercase();
+            match groups.iter_mut().find(|(key, _)| *key == folded) {
+                Some((_, keys)) => keys.push(&entry.key),
+                None => groups.push((folded, vec![&entry.key])),
+            }
+
+            let repeated = repeated_values(&entry.values);
+            if !repeated.is_empty() {
+                report
+                    .repeated_destinations
+                    .push((entry.key.clone(), repeated));
+            }
+        }
+
+        for (_, keys) in groups.into_iter().filter(|(_, keys)| keys.len() > 1) {
+            // Exact duplicates
+            for (index, key) in keys.iter().enumerate() {
+                if keys[..index].contains(key) && !report.duplicate_keys.iter().any(|x| x == key) {
+                    report.duplicate_keys.push(key.to_string());
+                }
+            }
+
+            // Different spellings of the same key
+            let mut spellings: Vec<String> = vec![];
+            for key in keys {
+                if !spellings.iter().any(|x| x == key) {
+                    spellings.push(key.to_owned());
+                }
+            }
+            if spellings.len() > 1 {
+                report.case_collisions.push(spellings);
+            }
+        }
+
+        report
+    }
+
+    // Merge every entry into the first entry with the same (case-insensitive) key, and remove repeated destinations.
+    // Postmap keeps the first of duplicated keys too, so the surviving entry is the one postfix was already using
+    pub fn normalize(&mut self) {
+        let mut lines: Vec<MapLine> = vec![];
+        // Position in `lines` of the first entry for every lowercase key
+        let mut first_entries: Vec<(String, usize)> = vec![];
+
+        for line in self.lines.drain(..) {
+            let MapLine::Entry(entry) = line else {
+                lines.push(line);
+                continue;
+            };
+
+            let folded = entry.key.to_lowercase();
+            match first_entries.iter().find(|(key, _)| *key == folded) {
+                // Move the values into the first entry, the comments inside the removed entry are kept in place
+                Some((_, index)) => {
+                    if let MapLine::Entry(first) = &mut lines[*index] {
+                        first.values.extend(entry.values);
+                        first.raw = None;
+                    }
+                    lines.extend(entry.interleaved.into_iter().map(MapLine::Verbatim));
+                }
+                None => {
+                    first_entries.push((folded, lines.len()));
+                    lines.push(MapLine::Entry(entry));
+                }
+            }
+        }
+
+        for line in lines.iter_mut() {
+            if let MapLine::Entry(entry) = line {
+                if !repeated_values(&entry.values).is_empty() {
+                    let mut values: Vec<String> = vec![];
+                    for value in entry.values.drain(..) {
+                        if !values.iter().any(|x| x.eq_ignore_ascii_case(&value)) {
+                            values.push(value);
+                        }
+                    }
+                    entry.values = values;
+                    entry.raw = None;
+                }
+            }
+        }
+
+        self.lines = lines;
+    }
+}
+
+// Values that appear more than once (ignoring case) in a list
+fn repeated_values(values: &[String]) -> Vec<String> {
+    let mut repeated: Vec<String> = vec![];
+
+    for (index, value) in values.iter().enumerate() {
+        if values[..index]
+            .iter()
+            .any(|x| x.eq_ignore_ascii_case(value))
+            && !repeated.iter().any(|x| x.eq_ignore_ascii_case(value))
+        {
+            repeated.push(value.clone());
+        }
+    }
+
+    repeated
+}
+
+// Problems found in a map that postfix would handle silently
+#[derive(Clone, Debug, Default)]
+pub struct NormalizationReport {
+    // Keys that appear more than once exactly as written
+    pub duplicate_keys: Vec<String>,
+    // Groups of keys that only differ by case (postfix lookups are case-insensitive)
+    pub case_collisions: Vec<Vec<String>>,
+    // Keys that list the same destination more than once, with the repeated destinations
+    pub repeated_destinations: Vec<(String, Vec<String>)>,
+    // Lines postfix warns about and skips, like keys without value
+    pub ignored_lines: Vec<ParseError>,
+}
+
+impl NormalizationReport {
+    pub fn is_empty(&self) -> bool {
+        self.duplicate_keys.is_empty()
+            && self.case_collisions.is_empty()
+            && self.repeated_destinations.is_empty()
+            && self.ignored_lines.is_empty()
+    }
+}
+
+// Render the whole document, tipically to be uploaded to the server
+impl Display for MapDocument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in self.lines.iter() {
+            match line {
+                MapLine::Verbatim(text) => f.write_str(text)?,
+                MapLine::Entry(entry) => f.write_str(&entry.render())?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(data: &str) -> MapDocument {
+        MapDocument::parse(data, Syntax::default()).unwrap()
+    }
+
+    fn keys(document: &MapDocument) -> Vec<&str> {
+        document.entries().map(|entry| entry.key()).collect()
+    }
+
+    #[test]
+    fn round_trip_is_byte_identical() {
+        for data in [
+            "",
+            "# only a comment\n",
+            "a@example.com b@example.com\n",
+            "a@example.com\tb@example.com,  c@example.com\n",
+            "a@example.com b@example.com\r\nc@example.com d@example.com\r\n",
+            "# header\n\na@example.com b@example.com,\n  c@example.com\n# trailing\n",
+            "a@example.com b@example.com,\n# between\n\tc@example.com\nd@example.com e@example.com",
+            "a@example.com b@example.com",
+        ] {
+            assert_eq!(parse(data).to_string(), data);
+        }
+    }
+
+    #[test]
+    fn continuation_lines_join_values() {
+        let document = parse("a@example.com b@example.com,\n# between\n\tc@example.com\n");
+
+        assert_eq!(
+            document.get("a@example.com").unwrap(),
+            ["b@example.com", "c@example.com"]
+        );
+    }
+
+    #[test]
+    fn keys_without_value_are_ignored_like_postmap_does() {
+        let data = "a@example.com\nb@example.com c@example.com\nd@example.com \n";
+        let document = parse(data);
+
+        assert_eq!(document.to_string(), data);
+        assert_eq!(
+            document.entries().map(|x| x.key()).collect::<Vec<_>>(),
+            ["b@example.com"]
+        );
+        let lines: Vec<usize> = document
+            .normalization_report()
+            .ignored_lines
+            .iter()
+            .map(|x| x.line)
+            .collect();
+        assert_eq!(lines, [1, 3]);
+
+        // aliases(5) lines without the ':' too
+        let syntax = Syntax {
+            key_terminator: Some(':'),
+            ..Default::default()
+        };
+        let document = MapDocument::parse("root\npostmaster: root\n", syntax).unwrap();
+        assert_eq!(document.entries().count(), 1);
+        assert_eq!(document.normalization_report().ignored_lines.len(), 1);
+    }
+
+    #[test]
+    fn leading_whitespace_is_an_error() {
+        let error =
+            MapDocument::parse(" a@example.com b@example.com\n", Syntax::default()).unwrap_err();
+
+        assert_eq!(error.line, 1);
+    }
+
+    #[test]
+    fn untouched_entries_keep_their_text() {
+        let mut document = parse("a@example.com   b@example.com\nc@example.com d@example.com\n");
+        document.set("c@example.com", vec!["e@example.com".to_owned()]);
+
+        assert_eq!(
+            document.to_string(),
+            "a@example.com   b@example.com\nc@example.com e@example.com\n"
+        );
+    }
+
+    #[test]
+    fn push_terminates_the_last_line() {
+        let mut document = parse("# comment\na@example.com b@example.com");
+        document.push("c@example.com", vec!["d@example.com".to_owned()]);
+
+        assert_eq!(
+            document.to_string(),
+            "# comment\na@example.com b@example.com\nc@example.com d@example.com\n"
+        );
+    }
+
+    #[test]
+    fn normalize_merges_duplicates() {
+        let mut document = parse("# map\na x\nb y, Y\nA z\nc v\na x, w\n");
+
+        let report = document.normalization_report();
+        assert_eq!(report.duplicate_keys, ["a"]);
+        assert_eq!(report.case_collisions, [vec!["a", "A"]]);
+        assert_eq!(
+            report.repeated_destinations,
+            [("b".to_owned(), vec!["Y".to_owned()])]
+        );
+
+        // Everything ends up in the first entry without repetitions, the other entries keep their order
+        document.normalize();
+        assert!(document.normalization_report().is_empty());
+        assert_eq!(document.to_string(), "# map\na x z w\nb y\nc v\n");
+    }
+
+    #[test]
+    fn entries_are_addressed_by_position() {
+        let mut document = parse("a x\n# comment\na y\nb z\n");
+
+        document.set_at(1, vec!["w".to_owned()]);
+        assert_eq!(document.to_string(), "a x\n# comment\na w\nb z\n");
+
+        document.rename_at(0, "c");
+        assert_eq!(keys(&document), ["c", "a", "b"]);
+
+        document.move_entry(2, 0);
+        assert_eq!(keys(&document), ["b", "c", "a"]);
+
+        document.remove_at(1);
+        assert_eq!(document.to_string(), "b z\n# comment\na w\n");
+
+        // Out of range positions change nothing
+        document.set_at(5, vec![]);
+        document.remove_at(5);
+        document.move_entry(0, 5);
+        assert_eq!(document.to_string(), "b z\n# comment\na w\n");
+    }
+}
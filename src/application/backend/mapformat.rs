@@ -0,0 +1,168 @@
+use std::fmt::{self, Display};
+
+use serde::Deserialize;
+
+use self::{
+    access::AccessFormat,
+    aliases::AliasesFormat,
+    canonical::CanonicalFormat,
+    regexp::{Dialect, RegexpFormat},
+    transport::TransportFormat,
+    virtualmap::VirtualFormat,
+};
+
+use super::{
+    mapdocument::{MapDocument, ParseError, Syntax},
+    resolver,
+};
+
+pub mod access;
+pub mod aliases;
+pub mod canonical;
+pub mod regexp;
+pub mod transport;
+pub mod virtualmap;
+
+// A problem found in a single entry of a map
+#[derive(Clone, Debug)]
+pub struct ValidationError {
+    // Key of the offending entry
+    pub key: String,
+    // Human readable description of the problem
+    pub message: String,
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+// The kind of lookup table stored in a server's config_path
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MapKind {
+    // virtual(5), the default
+    #[default]
+    Virtual,
+    // aliases(5), tipically /etc/aliases
+    Aliases,
+    // canonical(5) used as sender_canonical_maps
+    SenderCanonical,
+    // canonical(5) used as recipient_canonical_maps
+    RecipientCanonical,
+    // transport(5)
+    Transport,
+    // access(5), used by check_recipient_access, check_sender_access and friends
+    Access,
+    // regexp_table(5), an ordered list of POSIX regular expressions
+    Regexp,
+    // pcre_table(5), an ordered list of Perl compatible regular expressions
+    Pcre,
+}
+
+impl MapKind {
+    // Get the format implementation for this kind of table
+    pub fn format(&self) -> &'static dyn MapFormat {
+        match self {
+            Self::Virtual => &VirtualFormat,
+            Self::Aliases => &AliasesFormat,
+            Self::SenderCanonical | Self::RecipientCanonical => &CanonicalFormat,
+            Self::Transport => &TransportFormat,
+            Self::Access => &AccessFormat,
+            Self::Regexp => &RegexpFormat(Dialect::Posix),
+            Self::Pcre => &RegexpFormat(Dialect::Pcre),
+        }
+    }
+}
+
+// Everything needed to read, write and check one kind of postfix lookup table
+pub trait MapFormat: Sync {
+    // Lexical rules of the table
+    fn syntax(&self) -> Syntax;
+
+    // Label shown in the interface above the values of an entry
+    fn value_label(&self) -> &'static str;
+
+    // Whether the entries are rules tried in file order (the first match wins), instead of exact keys
+    fn ordered(&self) -> bool {
+        false
+    }
+
+    // Whether the values are looked up again in the same table, like aliases that point to other aliases
+    fn recursive(&self) -> bool {
+        false
+    }
+
+    // Keys tried, in order, when an address is looked up while expanding it. Empty for final recipients
+    fn lookup_keys(&self, _address: &str) -> Vec<String> {
+        vec![]
+    }
+
+    // Commands run as root after installing the map at path, unless the server configures its own.
+    // Hashed tables must be rebuilt with postmap before postfix sees the changes
+    fn post_install(&self, path: &str) -> Vec<String> {
+        vec![format!("postmap hash:{path}"), "postfix reload".to_owned()]
+    }
+
+    // Command run as root on a temporary copy of the new map at path, it must fail if postfix can't read the map.
+    // postmap without a type builds the server's default_database_type
+    fn preflight(&self, path: &str) -> Option<String> {
+        Some(format!("postmap {path}"))
+    }
+
+    // Check a single key
+    fn validate_key(&self, key: &str) -> Result<(), String>;
+
+    // Check a single value
+    fn validate_value(&self, value: &str) -> Result<(), String>;
+
+    // Parse the contents of a map file
+    fn parse(&self, data: &str) -> Result<MapDocument, ParseError> {
+        MapDocument::parse(data, self.syntax())
+    }
+
+    // Render the document to be uploaded to the server
+    fn serialize(&self, document: &MapDocument) -> String {
+        document.to_string()
+    }
+
+    // Check every entry of the document, returning all the problems found
+    fn validate(&self, document: &MapDocument) -> Vec<ValidationError> {
+        let mut errors = vec![];
+
+        for entry in document.entries() {
+            let mut error = |message: String| {
+                errors.push(ValidationError {
+                    key: entry.key().to_owned(),
+                    message,
+                })
+            };
+
+            if let Err(message) = self.validate_key(entry.key()) {
+                error(message);
+            }
+
+            if entry.values().is_empty() {
+                error("the entry has no values".to_owned());
+            }
+
+            if !self.syntax().list_values && entry.values().len() > 1 {
+                error("only one value is allowed".to_owned());
+            }
+
+            for value in entry.values() {
+                if let Err(message) = self.validate_value(value) {
+                    error(message);
+                }
+            }
+        }
+
+        // Loops and endless chains only show up when the entries are followed
+        if self.recursive() {
+            errors.extend(resolver::check(self, document));
+        }
+
+        errors
+    }
+}
@@ -0,0 +1,332 @@
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+use crate::application::backend::{
+    address::{Alias, Destination},
+    mapdocument::Syntax,
+};
+
+use super::MapFormat;
+
+// Whether an action accepts text after its keyword
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextRule {
+    None,
+    Optional,
+    Required,
+}
+
+// The actions an access(5) table can return
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessVerb {
+    Ok,
+    Dunno,
+    Reject,
+    Defer,
+    DeferIfReject,
+    DeferIfPermit,
+    Discard,
+    Hold,
+    Info,
+    Warn,
+    Filter,
+    Prepend,
+    Redirect,
+    Bcc,
+    // A 4NN or 5NN SMTP reply code followed by text
+    Code,
+    // A list of smtpd restrictions, like permit_mynetworks or reject_unauth_destination
+    Restriction,
+}
+
+impl AccessVerb {
+    // Every action, in the order they are offered in the interface
+    pub const ALL: [AccessVerb; 16] = [
+        Self::Ok,
+        Self::Reject,
+        Self::Code,
+        Self::Discard,
+        Self::Hold,
+        Self::Dunno,
+        Self::Defer,
+        Self::DeferIfReject,
+        Self::DeferIfPermit,
+        Self::Info,
+        Self::Warn,
+        Self::Filter,
+        Self::Prepend,
+        Self::Redirect,
+        Self::Bcc,
+        Self::Restriction,
+    ];
+
+    // Keyword written in the table (the code and the restrictions are written as they are)
+    pub fn keyword(&self) -> &'static str {
+        match self {
+            Self::Ok => "OK",
+            Self::Dunno => "DUNNO",
+            Self::Reject => "REJECT",
+            Self::Defer => "DEFER",
+            Self::DeferIfReject => "DEFER_IF_REJECT",
+            Self::DeferIfPermit => "DEFER_IF_PERMIT",
+            Self::Discard => "DISCARD",
+            Self::Hold => "HOLD",
+            Self::Info => "INFO",
+            Self::Warn => "WARN",
+            Self::Filter => "FILTER",
+            Self::Prepend => "PREPEND",
+            Self::Redirect => "REDIRECT",
+            Self::Bcc => "BCC",
+            Self::Code => "4NN/5NN",
+            Self::Restriction => "restricciones",
+        }
+    }
+
+    pub fn text_rule(&self) -> TextRule {
+        match self {
+            Self::Ok | Self::Dunno => TextRule::None,
+            Self::Reject
+            | Self::Defer
+            | Self::DeferIfReject
+            | Self::DeferIfPermit
+            | Self::Discard
+            | Self::Hold
+            | Self::Info
+            | Self::Warn => TextRule::Optional,
+            Self::Filter
+            | Self::Prepend
+            | Self::Redirect
+            | Self::Bcc
+            | Self::Code
+            | Self::Restriction => TextRule::Required,
+        }
+    }
+
+    // Hint shown next to the text input
+    pub fn text_hint(&self) -> &'static str {
+        match self {
+            Self::Filter => "transport:destination",
+            Self::Prepend => "Header-Name: value",
+            Self::Redirect | Self::Bcc => "user@example.com",
+            Self::Code => "550 5.7.1 text",
+            Self::Restriction => "permit_mynetworks, reject",
+            _ => "optional text",
+        }
+    }
+}
+
+// A complete access(5) action: a verb plus its text
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessAction {
+    pub verb: AccessVerb,
+    // Text after the keyword, for codes and restrictions it's the whole value
+    pub text: String,
+}
+
+impl AccessAction {
+    // Build an action from its parts, checking the text
+    pub fn new(verb: AccessVerb, text: &str) -> Result<Self, String> {
+        let text = text.trim();
+
+        match verb.text_rule() {
+            TextRule::None if !text.is_empty() => {
+                return Err(format!("{} doesn't accept text", verb.keyword()))
+            }
+            TextRule::Required if text.is_empty() => {
+                return Err(format!("{} requires {}", verb.keyword(), verb.text_hint()))
+            }
+            _ => {}
+        }
+
+        match verb {
+            AccessVerb::Code => {
+                let code = text.split_whitespace().next().unwrap_or_default();
+                if code.len() != 3
+                    || !code.starts_with(['4', '5'])
+                    || !code.chars().all(|x| x.is_ascii_digit())
+                {
+                    return Err(format!("'{code}' is not a 4NN or 5NN reply code"));
+                }
+            }
+            AccessVerb::Filter if !text.contains(':') => {
+                return Err(format!("expected transport:destination, got '{text}'"));
+            }
+            AccessVerb::Prepend => {
+                let valid = text.split_once(':').is_some_and(|(name, _)| {
+                    !name.is_empty() && name.chars().all(|x| x.is_ascii_graphic() && x != ':')
+                });
+                if !valid {
+                    return Err(format!("expected Header-Name: value, got '{text}'"));
+                }
+            }
+            AccessVerb::Redirect | AccessVerb::Bcc => match text.parse::<Destination>() {
+                Ok(Destination::Address { .. }) => {}
+                Ok(_) => return Err(format!("'{text}' must be a full address")),
+                Err(error) => return Err(error.to_string()),
+            },
+            AccessVerb::Restriction => {
+                if let Some(name) = text
+                    .split(|x: char| x.is_whitespace() || x == ',')
+                    .filter(|x| !x.is_empty())
+                    .find(|x| {
+                        !x.chars().all(|x| {
+                            x.is_ascii_lowercase() || x.is_ascii_digit() || "_:.-/".contains(x)
+                        })
+                    })
+                {
+                    return Err(format!("'{name}' is not a restriction name"));
+                }
+            }
+            _ => {}
+        }
+
+        Ok(Self {
+            verb,
+            text: text.to_owned(),
+        })
+    }
+}
+
+impl FromStr for AccessAction {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        let (word, text) = value.split_once(char::is_whitespace).unwrap_or((value, ""));
+
+        // Action keywords are case-insensitive
+        if let Some(verb) = AccessVerb::ALL.into_iter().find(|verb| {
+            !matches!(verb, AccessVerb::Code | AccessVerb::Restriction)
+                && verb.keyword().eq_ignore_ascii_case(word)
+        }) {
+            return Self::new(verb, text);
+        }
+
+        // Numerical reply codes and restriction names are written as they are
+        if word.starts_with(|x: char| x.is_ascii_digit()) {
+            return Self::new(AccessVerb::Code, value);
+        }
+        if word.starts_with(|x: char| x.is_ascii_lowercase()) {
+            return Self::new(AccessVerb::Restriction, value);
+        }
+
+        Err(format!("unknown access action '{word}'"))
+    }
+}
+
+impl Display for AccessAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.verb {
+            AccessVerb::Code | AccessVerb::Restriction => f.write_str(&self.text),
+            verb if self.text.is_empty() => f.write_str(verb.keyword()),
+            verb => write!(f, "{} {}", verb.keyword(), self.text),
+        }
+    }
+}
+
+// access(5): `pattern action`, used by check_recipient_access, check_sender_access and friends
+pub struct AccessFormat;
+
+impl MapFormat for AccessFormat {
+    fn syntax(&self) -> Syntax {
+        Syntax {
+            list_values: false,
+            ..Default::default()
+        }
+    }
+
+    fn value_label(&self) -> &'static str {
+        "Acción"
+    }
+
+    // Patterns are addresses, domains, user@ parts and (partial) IP addresses
+    fn validate_key(&self, key: &str) -> Result<(), String> {
+        // 192.168, 10.0.0.1, 2001:db8::1
+        let is_ip = (key.split('.').count() <= 4
+            && key
+                .split('.')
+                .all(|x| !x.is_empty() && x.len() <= 3 && x.chars().all(|x| x.is_ascii_digit())))
+            || (key.contains(':') && key.chars().all(|x| x.is_ascii_hexdigit() || x == ':'));
+        if is_ip {
+            return Ok(());
+        }
+
+        // user@ matches the user part in any domain
+        if let Some(local) = key.strip_suffix('@') {
+            return local
+                .parse::<Destination>()
+                .map(|_| ())
+                .map_err(|error| error.to_string());
+        }
+
+        match key.strip_prefix('.').unwrap_or(key).parse::<Alias>() {
+            Ok(Alias::Address { .. } | Alias::Domain(_)) => Ok(()),
+            Ok(_) => Err(format!(
+                "'{key}' must be an address, a domain, user@ or an IP address"
+            )),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    fn validate_value(&self, value: &str) -> Result<(), String> {
+        value.parse::<AccessAction>().map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(value: &str) -> Result<AccessAction, String> {
+        value.parse()
+    }
+
+    #[test]
+    fn actions_are_parsed() {
+        assert_eq!(parse("OK").unwrap().verb, AccessVerb::Ok);
+        assert_eq!(parse("reject").unwrap().verb, AccessVerb::Reject);
+
+        let action = parse("REJECT  no thanks").unwrap();
+        assert_eq!(action.verb, AccessVerb::Reject);
+        assert_eq!(action.text, "no thanks");
+
+        assert_eq!(parse("550 5.7.1 go away").unwrap().verb, AccessVerb::Code);
+        assert_eq!(
+            parse("permit_mynetworks, reject").unwrap().verb,
+            AccessVerb::Restriction
+        );
+        assert_eq!(
+            parse("REDIRECT user@example.com").unwrap().verb,
+            AccessVerb::Redirect
+        );
+    }
+
+    #[test]
+    fn invalid_actions_are_rejected() {
+        for value in [
+            "OK but",
+            "FILTER",
+            "FILTER nocolon",
+            "PREPEND no header",
+            "REDIRECT user",
+            "250 fine",
+            "Permit",
+        ] {
+            assert!(parse(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn actions_render_back() {
+        for value in [
+            "OK",
+            "REJECT no thanks",
+            "550 5.7.1 go away",
+            "permit_mynetworks, reject",
+        ] {
+            assert_eq!(parse(value).unwrap().to_string(), value);
+        }
+    }
+}
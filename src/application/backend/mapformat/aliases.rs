@@ -0,0 +1,183 @@
+use crate::application::backend::{address::Destination, mapdocument::Syntax};
+
+use super::MapFormat;
+
+// aliases(5): `name: value, value...`, where values can also be commands, include files and mailbox files
+pub struct AliasesFormat;
+
+impl MapFormat for AliasesFormat {
+    fn syntax(&self) -> Syntax {
+        Syntax {
+            key_terminator: Some(':'),
+            list_values: true,
+            separator: ": ",
+            value_separator: ", ",
+            pattern_keys: false,
+        }
+    }
+
+    fn value_label(&self) -> &'static str {
+        "Destinos"
+    }
+
+    fn recursive(&self) -> bool {
+        true
+    }
+
+    // The aliases database is built with postalias, like newaliases does
+    fn post_install(&self, path: &str) -> Vec<String> {
+        vec![
+            format!("postalias hash:{path}"),
+            "postfix reload".to_owned(),
+        ]
+    }
+
+    // postmap would take the aliases(5) syntax for an ordinary table, postalias rejects what newaliases would
+    fn preflight(&self, path: &str) -> Option<String> {
+        Some(format!("postalias {path}"))
+    }
+
+    // Only local names are aliases, commands, files, includes, \user and remote addresses are delivered as they are
+    fn lookup_keys(&self, address: &str) -> Vec<String> {
+        let address = address.trim_matches('"');
+        if address.starts_with(['|', '/', '\\'])
+            || address.starts_with(":include:")
+            || address.contains('@')
+        {
+            vec![]
+        } else {
+            vec![address.to_owned()]
+        }
+    }
+
+    fn validate_key(&self, key: &str) -> Result<(), String> {
+        // Local names only, the domain is implicit
+        if key.contains('@') {
+            return Err(format!("'{key}' must be a local name, without domain"));
+        }
+
+        key.parse::<Destination>()
+            .map(|_| ())
+            .map_err(|error| error.to_string())
+    }
+
+    fn validate_value(&self, value: &str) -> Result<(), String> {
+        // Values with whitespace must be quoted, the quotes aren't part of the value
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+
+        // Deliver to a command: |"/usr/bin/procmail -a foo"
+        if let Some(command) = value.strip_prefix('|') {
+            if command.trim().is_empty() {
+                return Err("the command is empty".to_owned());
+            }
+
+            return Ok(());
+        }
+
+        // Read the destinations from a file: :include:/etc/postfix/list
+        if let Some(path) = value.strip_prefix(":include:") {
+            if !path.starts_with('/') {
+                return Err(format!(
+                    "the include file '{path}' must be an absolute path"
+                ));
+            }
+
+            return Ok(());
+        }
+
+        // Append to a mailbox file: /var/mail/archive
+        if value.starts_with('/') {
+            return Ok(());
+        }
+
+        // \user delivers to the local user without expanding the alias again
+        value
+            .strip_prefix('\\')
+            .unwrap_or(value)
+            .parse::<Destination>()
+            .map(|_| ())
+            .map_err(|error| error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(data: &str) -> Vec<String> {
+        AliasesFormat
+            .validate(&AliasesFormat.parse(data).unwrap())
+            .into_iter()
+            .map(|error| error.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn keys_end_at_the_colon() {
+        let data = "postmaster:root\nlist :\tadmin, \\admin\nroot: admin@example.com\n";
+        let document = AliasesFormat.parse(data).unwrap();
+
+        let entries: Vec<(&str, &[String])> = document
+            .entries()
+            .map(|entry| (entry.key(), entry.values()))
+            .collect();
+        assert_eq!(entries[0], ("postmaster", &["root".to_owned()][..]));
+        assert_eq!(entries[1].0, "list");
+        assert_eq!(entries[1].1, ["admin", "\\admin"]);
+        assert_eq!(document.to_string(), data);
+        assert!(messages(data).is_empty());
+    }
+
+    #[test]
+    fn special_destinations_are_accepted() {
+        let document = AliasesFormat
+            .parse(
+                "archive: |\"/usr/bin/procmail -a archive\", :include:/etc/postfix/list, \
+                 /var/mail/archive, \\root\n",
+            )
+            .unwrap();
+
+        assert_eq!(
+            document.get("archive").unwrap(),
+            [
+                "|\"/usr/bin/procmail -a archive\"",
+                ":include:/etc/postfix/list",
+                "/var/mail/archive",
+                "\\root",
+            ]
+        );
+        assert!(AliasesFormat.validate(&document).is_empty());
+    }
+
+    #[test]
+    fn invalid_entries_are_reported() {
+        assert_eq!(
+            messages("user@example.com: root\n"),
+            ["user@example.com: 'user@example.com' must be a local name, without domain"]
+        );
+        assert_eq!(messages("a: |\n"), ["a: the command is empty"]);
+        assert_eq!(
+            messages("a: :include:list\n"),
+            ["a: the include file 'list' must be an absolute path"]
+        );
+        assert_eq!(messages("a: @example.com\n").len(), 1);
+    }
+
+    #[test]
+    fn only_local_names_are_expanded() {
+        assert_eq!(AliasesFormat.lookup_keys("admin"), ["admin"]);
+        assert_eq!(AliasesFormat.lookup_keys("\"admin\""), ["admin"]);
+        for address in [
+            "admin@example.com",
+            "|/usr/bin/procmail",
+            "/var/mail/archive",
+            ":include:/etc/postfix/list",
+            "\\admin",
+        ] {
+            assert!(AliasesFormat.lookup_keys(address).is_empty(), "{address}");
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use crate::application::backend::{address::Alias, mapdocument::Syntax};
+
+use super::MapFormat;
+
+// canonical(5), used for both sender_canonical_maps and recipient_canonical_maps: `pattern address`
+pub struct CanonicalFormat;
+
+impl MapFormat for CanonicalFormat {
+    fn syntax(&self) -> Syntax {
+        Syntax {
+            list_values: false,
+            ..Default::default()
+        }
+    }
+
+    fn value_label(&self) -> &'static str {
+        "Reescribir como"
+    }
+
+    fn validate_key(&self, key: &str) -> Result<(), String> {
+        key.parse::<Alias>()
+            .map(|_| ())
+            .map_err(|error| error.to_string())
+    }
+
+    // The result is an address, or @domain to keep the user part and replace the domain
+    fn validate_value(&self, value: &str) -> Result<(), String> {
+        match value.parse::<Alias>() {
+            Ok(Alias::Domain(domain)) => Err(format!(
+                "'{domain}' is a bare domain, use '@{domain}' to replace only the domain"
+            )),
+            Ok(_) => Ok(()),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_lookup_key_form_is_accepted() {
+        for key in ["user@example.com", "@example.com", "user", "example.com"] {
+            assert!(CanonicalFormat.validate_key(key).is_ok(), "{key}");
+        }
+        assert!(CanonicalFormat.validate_key("user@").is_err());
+    }
+
+    #[test]
+    fn results_are_an_address_or_a_domain_part() {
+        assert!(CanonicalFormat
+            .validate_value("john.doe@example.com")
+            .is_ok());
+        assert!(CanonicalFormat.validate_value("@example.org").is_ok());
+        assert_eq!(
+            CanonicalFormat.validate_value("example.org"),
+            Err(
+                "'example.org' is a bare domain, use '@example.org' to replace only the domain"
+                    .to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn entries_have_a_single_result() {
+        let document = CanonicalFormat
+            .parse("jdoe john.doe@example.com\n@old.example.com @example.com\n")
+            .unwrap();
+
+        assert_eq!(document.get("jdoe").unwrap(), ["john.doe@example.com"]);
+        assert_eq!(document.get("@old.example.com").unwrap(), ["@example.com"]);
+        assert!(CanonicalFormat.validate(&document).is_empty());
+        // The result is rewritten once, it isn't looked up again
+        assert!(!CanonicalFormat.recursive());
+        assert!(CanonicalFormat.lookup_keys("jdoe").is_empty());
+    }
+}
@@ -0,0 +1,631 @@
+use fancy_regex::{Captures, Regex};
+
+use crate::application::backend::{
+    address::Destination,
+    mapdocument::{split_pattern_key, MapDocument, PatternKey, Syntax},
+};
+
+use super::{MapFormat, ValidationError};
+
+// The regular expression dialect of a pattern table
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dialect {
+    // regexp_table(5), POSIX extended regular expressions
+    Posix,
+    // pcre_table(5), Perl compatible regular expressions
+    Pcre,
+}
+
+// What a line of a pattern table does
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RuleKind {
+    // `/pattern/ result`: the first matching rule gives the result
+    Match,
+    // `if /pattern/`: the rules up to the matching endif are only tried if the pattern matches
+    If,
+    // `endif`
+    Endif,
+}
+
+// A compiled line of a pattern table
+pub struct Rule {
+    pub kind: RuleKind,
+    // The rule matches when the pattern doesn't (`!/pattern/`)
+    pub negated: bool,
+    // Not present for endif
+    regex: Option<Regex>,
+}
+
+// A line of a pattern table split into its parts, before the pattern is compiled
+struct Pattern {
+    kind: RuleKind,
+    negated: bool,
+    // The expression for the local engine, None for endif
+    expression: Option<String>,
+}
+
+impl Pattern {
+    // Split the key of a line, translating the table flags into inline flags and POSIX expressions into the syntax
+    // of the local engine
+    fn parse(key: &str, dialect: Dialect) -> Result<Self, String> {
+        let key = key.trim();
+        let (parts, length) = split_pattern_key(key)?;
+        if length != key.len() {
+            return Err(format!(
+                "unexpected '{}' after the pattern",
+                key[length..].trim()
+            ));
+        }
+
+        let PatternKey::Pattern {
+            condition,
+            negated,
+            delimiter,
+            body,
+            flags,
+        } = parts
+        else {
+            return Ok(Self {
+                kind: RuleKind::Endif,
+                negated: false,
+                expression: None,
+            });
+        };
+
+        // Escaped delimiters are part of the pattern
+        let body = body.replace(&format!("\\{delimiter}"), &delimiter.to_string());
+
+        // Lookups are case-insensitive unless the i flag toggles it
+        let mut case_insensitive = true;
+        let mut inline = String::new();
+        let mut anchored = false;
+        // regexp tables use extended expressions unless the x flag toggles it
+        let mut basic = false;
+        for flag in flags.chars() {
+            match (flag, dialect) {
+                ('i', _) => case_insensitive = !case_insensitive,
+                ('m', _) => inline.push('m'),
+                ('x', Dialect::Posix) => basic = !basic,
+                ('x', Dialect::Pcre) => inline.push('x'),
+                ('s', Dialect::Pcre) => inline.push('s'),
+                ('U', Dialect::Pcre) => inline.push('U'),
+                ('A', Dialect::Pcre) => anchored = true,
+                // Only change details the local engine already behaves like
+                ('E' | 'X', Dialect::Pcre) => {}
+                _ => return Err(format!("unknown flag '{flag}'")),
+            }
+        }
+        if case_insensitive {
+            inline.push('i');
+        }
+
+        let body = match dialect {
+            Dialect::Posix => posix_expression(&body, basic)?,
+            Dialect::Pcre => body,
+        };
+
+        let mut expression = String::new();
+        if !inline.is_empty() {
+            expression += &format!("(?{inline})");
+        }
+        if anchored {
+            expression += &format!("\\A(?:{body})");
+        } else {
+            expression += &body;
+        }
+
+        Ok(Self {
+            kind: if condition {
+                RuleKind::If
+            } else {
+                RuleKind::Match
+            },
+            negated,
+            expression: Some(expression),
+        })
+    }
+}
+
+// Translate a POSIX regular expression, extended or basic, into the syntax of the local engine. Bracket expressions,
+// the anchors and operators of basic expressions and the GNU escapes of regcomp(3) are rewritten, the rest is the same
+fn posix_expression(body: &str, basic: bool) -> Result<String, String> {
+    let characters: Vec<char> = body.chars().collect();
+    let mut output = String::new();
+    let mut index = 0;
+
+    while index < characters.len() {
+        let character = characters[index];
+        index += 1;
+
+        // Basic expressions only have anchors at the ends of the expression and its groups, and * is literal at
+        // their start
+        let starts_group = |at: usize| {
+            at == 0 || (at >= 2 && characters[at - 2] == '\\' && characters[at - 1] == '(')
+        };
+        let at_start = starts_group(index - 1);
+        let after_anchor = index >= 2 && characters[index - 2] == '^' && starts_group(index - 2);
+        let at_end = index == characters.len()
+            || (characters.get(index) == Some(&'\\') && characters.get(index + 1) == Some(&')'));
+
+        match character {
+            '[' => index = bracket_expression(&characters, index, &mut output)?,
+            '\\' => {
+                let next = *characters
+                    .get(index)
+                    .ok_or_else(|| "trailing backslash in the pattern".to_owned())?;
+                index += 1;
+
+                match next {
+                    // Groups, intervals and the GNU operators are escaped in basic expressions
+                    '(' | ')' | '{' | '}' | '+' | '?' | '|' if basic => output.push(next),
+                    '<' => output += "\\b(?=\\w)",
+                    '>' => output += "\\b(?<=\\w)",
+                    '`' => output += "\\A",
+                    '\'' => output += "\\z",
+                    'w' | 'W' | 's' | 'S' | 'b' | 'B' | '1'..='9' => {
+                        output.push('\\');
+                        output.push(next);
+                    }
+                    // Any other escaped character stands for itself
+                    _ => push_literal(&mut output, next),
+                }
+            }
+            '(' | ')' | '{' | '}' | '+' | '?' | '|' if basic => {
+                push_literal(&mut output, character)
+            }
+            '^' if basic && !at_start => push_literal(&mut output, character),
+            '$' if basic && !at_end => push_literal(&mut output, character),
+            '*' if basic && (at_start || after_anchor) => push_literal(&mut output, character),
+            _ => output.push(character),
+        }
+    }
+
+    Ok(output)
+}
+
+// Translate a bracket expression starting after its '[', returning where it ends. Backslashes are literal inside
+// POSIX brackets, and nested brackets and set operators of the local engine must be escaped
+fn bracket_expression(
+    characters: &[char],
+    mut index: usize,
+    output: &mut String,
+) -> Result<usize, String> {
+    output.push('[');
+    if characters.get(index) == Some(&'^') {
+        output.push('^');
+        index += 1;
+    }
+    // A ']' right after the opening is part of the list
+    if characters.get(index) == Some(&']') {
+        output.push_str("\\]");
+        index += 1;
+    }
+
+    loop {
+        let character = *characters
+            .get(index)
+            .ok_or_else(|| "unterminated bracket expression".to_owned())?;
+        index += 1;
+
+        match character {
+            ']' => {
+                output.push(']');
+                return Ok(index);
+            }
+            // [:class:], [=equivalence=] and [.collating.]
+            '[' if matches!(characters.get(index), Some(':' | '=' | '.')) => {
+                let kind = characters[index];
+                let start = index + 1;
+                let end = (start..characters.len().saturating_sub(1))
+                    .find(|&end| characters[end] == kind && characters[end + 1] == ']')
+                    .ok_or_else(|| format!("unterminated '[{kind}' in bracket expression"))?;
+                let name: String = characters[start..end].iter().collect();
+                index = end + 2;
+
+                if kind == ':' {
+                    // The local engine would take an unknown class as a nested list
+                    if !POSIX_CLASSES.contains(&name.as_str()) {
+                        return Err(format!("unknown character class '{name}'"));
+                    }
+                    output.push_str(&format!("[:{name}:]"));
+                } else {
+                    // Only single characters, which are their own equivalence class in the C locale
+                    let mut name = name.chars();
+                    match (name.next(), name.next()) {
+                        (Some(character), None) => push_class_literal(output, character),
+                        _ => return Err(format!("unsupported collating element in '[{kind}'")),
+                    }
+                }
+            }
+            _ => push_class_literal(output, character),
+        }
+    }
+}
+
+// The character classes of regcomp(3)
+const POSIX_CLASSES: [&str; 12] = [
+    "alnum", "alpha", "blank", "cntrl", "digit", "graph", "lower", "print", "punct", "space",
+    "upper", "xdigit",
+];
+
+// Append a character that must match literally
+fn push_literal(output: &mut String, character: char) {
+    if "\\.+*?()|[]{}^$".contains(character) {
+        output.push('\\');
+    }
+    output.push(character);
+}
+
+// Append a character of a bracket expression, escaping what the local engine would take as syntax
+fn push_class_literal(output: &mut String, character: char) {
+    if "\\[&~".contains(character) {
+        output.push('\\');
+    }
+    output.push(character);
+}
+
+impl Rule {
+    // Compile the key of a line with the local engine
+    pub fn compile(key: &str, dialect: Dialect) -> Result<Self, String> {
+        let pattern = Pattern::parse(key, dialect)?;
+
+        let regex = match &pattern.expression {
+            Some(expression) => Some(Regex::new(expression).map_err(|error| error.to_string())?),
+            None => None,
+        };
+
+        Ok(Self {
+            kind: pattern.kind,
+            negated: pattern.negated,
+            regex,
+        })
+    }
+
+    // Number of capture groups that can be used in the result
+    fn groups(&self) -> usize {
+        match &self.regex {
+            Some(regex) if !self.negated => regex.captures_len() - 1,
+            _ => 0,
+        }
+    }
+
+    // Match an address, returning the captures if the rule applies
+    fn captures<'a>(&self, address: &'a str) -> Result<Option<Option<Captures<'a>>>, String> {
+        let Some(regex) = &self.regex else {
+            return Ok(None);
+        };
+
+        let captures = regex.captures(address).map_err(|error| error.to_string())?;
+        Ok(match (captures, self.negated) {
+            (Some(captures), false) => Some(Some(captures)),
+            (None, true) => Some(None),
+            _ => None,
+        })
+    }
+}
+
+// Replace $n, ${n} and $(n) in a result with the text of a group, and $$ with $
+fn substitute(
+    result: &str,
+    mut group: impl FnMut(usize) -> Result<String, String>,
+) -> Result<String, String> {
+    let mut output = String::new();
+    let mut rest = result;
+
+    while let Some(start) = rest.find('$') {
+        output += &rest[..start];
+        rest = &rest[start + 1..];
+
+        if let Some(after) = rest.strip_prefix('$') {
+            output.push('$');
+            rest = after;
+            continue;
+        }
+
+        let (number, after) = match rest.chars().next() {
+            Some(open @ ('{' | '(')) => {
+                let close = if open == '{' { '}' } else { ')' };
+                let end = rest
+                    .find(close)
+                    .ok_or_else(|| format!("unterminated '${open}' in '{result}'"))?;
+                (&rest[1..end], &rest[end + 1..])
+            }
+            _ => {
+                let end = rest
+                    .find(|x: char| !x.is_ascii_digit())
+                    .unwrap_or(rest.len());
+                (&rest[..end], &rest[end..])
+            }
+        };
+
+        let number = number
+            .parse::<usize>()
+            .map_err(|_| format!("invalid '$' in '{result}', write '$$' for a literal '$'"))?;
+        output += &group(number)?;
+        rest = after;
+    }
+
+    Ok(output + rest)
+}
+
+// The rule that matched an address while testing a table
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuleMatch {
+    // Position of the rule among the entries of the table
+    pub index: usize,
+    pub key: String,
+    // The result with the groups replaced
+    pub result: String,
+}
+
+// regexp_table(5) and pcre_table(5): `/pattern/flags result`, tried in file order until one matches
+pub struct RegexpFormat(pub Dialect);
+
+impl RegexpFormat {
+    // Find the first rule that matches an address, like postfix does, and expand its result
+    pub fn evaluate(
+        &self,
+        document: &MapDocument,
+        address: &str,
+    ) -> Result<Option<RuleMatch>, String> {
+        // Number of if blocks being skipped because their pattern didn't match
+        let mut skipping = 0;
+
+        for (index, entry) in document.entries().enumerate() {
+            let rule = Rule::compile(entry.key(), self.0)
+                .map_err(|error| format!("{}: {error}", entry.key()))?;
+
+            if skipping > 0 {
+                match rule.kind {
+                    RuleKind::If => skipping += 1,
+                    RuleKind::Endif => skipping -= 1,
+                    RuleKind::Match => {}
+                }
+                continue;
+            }
+
+            let captures = rule.captures(address)?;
+            match rule.kind {
+                RuleKind::If if captures.is_none() => skipping = 1,
+                RuleKind::If | RuleKind::Endif => {}
+                RuleKind::Match => {
+                    let Some(captures) = captures else {
+                        continue;
+                    };
+
+                    let result = entry.values().first().cloned().unwrap_or_default();
+                    let result = substitute(&result, |number| {
+                        Ok(captures
+                            .as_ref()
+                            .and_then(|captures| captures.get(number))
+                            .map(|group| group.as_str().to_owned())
+                            .unwrap_or_default())
+                    })?;
+
+                    return Ok(Some(RuleMatch {
+                        index,
+                        key: entry.key().to_owned(),
+                        result,
+                    }));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl MapFormat for RegexpFormat {
+    fn syntax(&self) -> Syntax {
+        Syntax {
+            list_values: false,
+            pattern_keys: true,
+            ..Default::default()
+        }
+    }
+
+    fn value_label(&self) -> &'static str {
+        "Resultado"
+    }
+
+    fn ordered(&self) -> bool {
+        true
+    }
+
+    // The patterns are read from the file itself, there is no database to build
+    fn post_install(&self, _path: &str) -> Vec<String> {
+        vec!["postfix reload".to_owned()]
+    }
+
+    // postmap compiles every pattern when it opens the table, a query without keys only opens it
+    fn preflight(&self, path: &str) -> Option<String> {
+        let kind = match self.0 {
+            Dialect::Posix => "regexp",
+            Dialect::Pcre => "pcre",
+        };
+        Some(format!("postmap -q - {kind}:{path} </dev/null"))
+    }
+
+    // POSIX patterns are translated before being compiled, so their mistakes are found too
+    fn validate_key(&self, key: &str) -> Result<(), String> {
+        Rule::compile(key, self.0).map(|_| ())
+    }
+
+    // The result is a list of destinations, any of them may be built from the groups of the pattern
+    fn validate_value(&self, value: &str) -> Result<(), String> {
+        substitute(value, |_| Ok(String::new()))?;
+
+        for destination in value
+            .split(|x: char| x == ',' || x.is_whitespace())
+            .filter(|x| !x.is_empty() && !x.contains('$'))
+        {
+            destination
+                .parse::<Destination>()
+                .map_err(|error| error.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    // Besides every line on its own, check the groups used by the results and that if and endif are balanced
+    fn validate(&self, document: &MapDocument) -> Vec<ValidationError> {
+        let mut errors = vec![];
+        let mut open_blocks: Vec<String> = vec![];
+
+        for entry in document.entries() {
+            let mut error = |message: String| {
+                errors.push(ValidationError {
+                    key: entry.key().to_owned(),
+                    message,
+                })
+            };
+
+            let rule = match Rule::compile(entry.key(), self.0) {
+                Ok(rule) => rule,
+                Err(message) => {
+                    error(message);
+                    continue;
+                }
+            };
+            let (kind, groups) = (rule.kind, rule.groups());
+
+            match kind {
+                RuleKind::If | RuleKind::Endif if !entry.values().is_empty() => {
+                    error("if and endif lines don't have a result".to_owned())
+                }
+                RuleKind::Match if entry.values().is_empty() => {
+                    error("the rule has no result".to_owned())
+                }
+                _ => {}
+            }
+
+            match kind {
+                RuleKind::If => open_blocks.push(entry.key().to_owned()),
+                RuleKind::Endif if open_blocks.pop().is_none() => {
+                    error("endif without if".to_owned())
+                }
+                _ => {}
+            }
+
+            for value in entry.values() {
+                if let Err(message) = self.validate_value(value) {
+                    error(message);
+                }
+
+                if let Err(message) = substitute(value, |number| {
+                    if number > groups {
+                        Err(format!("the pattern has no group {number}"))
+                    } else {
+                        Ok(String::new())
+                    }
+                }) {
+                    error(message);
+                }
+            }
+        }
+
+        for key in open_blocks {
+            errors.push(ValidationError {
+                key,
+                message: "if without endif".to_owned(),
+            });
+        }
+
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: &str = "\
+if /@example\\.com$/
+/^(.*)\\.old@/ ${1}@example.com
+/^postmaster@/ admin@example.com
+endif
+/^(.*)@/ $1@fallback.example.com
+";
+
+    fn messages(format: &RegexpFormat, data: &str) -> Vec<String> {
+        format
+            .validate(&format.parse(data).unwrap())
+            .into_iter()
+            .map(|error| error.message)
+            .collect()
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let format = RegexpFormat(Dialect::Pcre);
+        let document = format.parse(TABLE).unwrap();
+
+        let rule = format
+            .evaluate(&document, "john.old@example.com")
+            .unwrap()
+            .unwrap();
+        assert_eq!(rule.index, 1);
+        assert_eq!(rule.result, "john@example.com");
+
+        // The if block is skipped for other domains
+        let rule = format
+            .evaluate(&document, "postmaster@example.org")
+            .unwrap()
+            .unwrap();
+        assert_eq!(rule.index, 4);
+        assert_eq!(rule.result, "postmaster@fallback.example.com");
+
+        assert_eq!(format.evaluate(&document, "nobody").unwrap(), None);
+    }
+
+    #[test]
+    fn valid_tables_have_no_errors() {
+        for dialect in [Dialect::Posix, Dialect::Pcre] {
+            assert!(messages(&RegexpFormat(dialect), TABLE).is_empty());
+        }
+    }
+
+    #[test]
+    fn structure_and_groups_are_checked() {
+        let format = RegexpFormat(Dialect::Pcre);
+
+        assert_eq!(
+            messages(&format, "/^(.*)@/ $2@example.com\n"),
+            ["the pattern has no group 2"]
+        );
+        assert_eq!(messages(&format, "endif\n"), ["endif without if"]);
+        assert_eq!(messages(&format, "if /a/\n"), ["if without endif"]);
+        assert_eq!(messages(&format, "/a/\n"), ["the rule has no result"]);
+        assert_eq!(messages(&format, "/(a/ x@example.com\n").len(), 1);
+    }
+
+    #[test]
+    fn posix_patterns_are_compiled() {
+        let posix = RegexpFormat(Dialect::Posix);
+        assert!(posix.validate_key("/(a/").is_err());
+        assert!(posix.validate_key("/[z-a]/").is_err());
+        assert!(posix.validate_key("/[[:nope:]]/").is_err());
+        assert_eq!(messages(&posix, "/(a/ x@example.com\n").len(), 1);
+
+        // Bracket expressions, word boundaries and basic expressions (flag x) are translated
+        let document = posix
+            .parse(concat!(
+                "/^[]a\\]+$/ brackets\n",
+                "/^[[:digit:]]+\\>/ digits\n",
+                "/^\\(a*\\)+b(c)$/x basic:$1\n",
+                "/^*a$/x star\n",
+            ))
+            .unwrap();
+        let result = |key: &str| {
+            posix
+                .evaluate(&document, key)
+                .unwrap()
+                .map(|rule| rule.result)
+        };
+        assert_eq!(result("a]\\").as_deref(), Some("brackets"));
+        assert_eq!(result("42 x").as_deref(), Some("digits"));
+        assert_eq!(result("42x"), None);
+        assert_eq!(result("aa+b(c)").as_deref(), Some("basic:aa"));
+        assert_eq!(result("*A").as_deref(), Some("star"));
+    }
+}
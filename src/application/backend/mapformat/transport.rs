@@ -0,0 +1,277 @@
+use std::{
+    fmt::{self, Display},
+    net::IpAddr,
+    str::FromStr,
+};
+
+use crate::application::backend::{
+    address::{validate_domain, Alias},
+    mapdocument::Syntax,
+};
+
+use super::MapFormat;
+
+// Transports offered in the interface, other transports defined in master.cf are accepted when parsing
+pub const TRANSPORTS: [&str; 5] = ["smtp", "relay", "lmtp", "error", "discard"];
+
+// The part after the ':' in a transport(5) result
+#[derive(Clone, Debug, PartialEq)]
+pub enum Nexthop {
+    // Nothing after the ':', the transport uses its default destination
+    Default,
+    // host, host:port, [host] or [host]:port
+    Host {
+        host: String,
+        port: Option<u16>,
+        // [host] skips the MX lookup
+        mx_lookup: bool,
+    },
+    // lmtp over a unix socket: unix:/path
+    Socket(String),
+    // Free text for the error and discard transports
+    Text(String),
+}
+
+// A parsed transport(5) result: `transport:nexthop`
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransportRoute {
+    // Empty to keep the default transport
+    pub transport: String,
+    pub nexthop: Nexthop,
+}
+
+// Parse host, host:port, [host] and [host]:port
+fn parse_host(text: &str) -> Result<Nexthop, String> {
+    let (host, port, mx_lookup) = match text.strip_prefix('[') {
+        Some(rest) => {
+            let (host, rest) = rest
+                .split_once(']')
+                .ok_or_else(|| format!("missing ']' in '{text}'"))?;
+            let port = match rest {
+                "" => None,
+                port => Some(
+                    port.strip_prefix(':')
+                        .ok_or_else(|| format!("unexpected '{port}' after ']'"))?,
+                ),
+            };
+            (host, port, false)
+        }
+        None => match text.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port), true),
+            None => (text, None, true),
+        },
+    };
+
+    // Bracketed hosts may also be IP addresses, IPv6 ones with an ipv6: prefix
+    let is_ip = !mx_lookup
+        && host
+            .strip_prefix("ipv6:")
+            .unwrap_or(host)
+            .parse::<IpAddr>()
+            .is_ok();
+    if !is_ip {
+        validate_domain(host).map_err(|error| error.to_string())?;
+    }
+
+    let port = match port {
+        Some(port) => Some(
+            port.parse::<u16>()
+                .ok()
+                .filter(|port| *port != 0)
+                .ok_or_else(|| format!("'{port}' is not a valid port"))?,
+        ),
+        None => None,
+    };
+
+    Ok(Nexthop::Host {
+        host: host.to_owned(),
+        port,
+        mx_lookup,
+    })
+}
+
+impl TransportRoute {
+    // Build a route from its parts, checking the nexthop syntax for the transport
+    pub fn new(transport: &str, nexthop: &str) -> Result<Self, String> {
+        let transport = transport.trim();
+        let nexthop = nexthop.trim();
+
+        if !transport
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || x == '_' || x == '-')
+        {
+            return Err(format!("'{transport}' is not a valid transport name"));
+        }
+
+        let nexthop = match transport {
+            "error" | "discard" => Nexthop::Text(nexthop.to_owned()),
+            _ if nexthop.is_empty() => Nexthop::Default,
+            "lmtp" => match nexthop.strip_prefix("unix:") {
+                Some(path) if path.starts_with('/') => Nexthop::Socket(path.to_owned()),
+                Some(path) => return Err(format!("the socket '{path}' must be an absolute path")),
+                None => parse_host(nexthop.strip_prefix("inet:").unwrap_or(nexthop))?,
+            },
+            _ => parse_host(nexthop)?,
+        };
+
+        Ok(Self {
+            transport: transport.to_owned(),
+            nexthop,
+        })
+    }
+}
+
+impl FromStr for TransportRoute {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (transport, nexthop) = value
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| format!("expected format: transport:nexthop, got '{value}'"))?;
+
+        Self::new(transport, nexthop)
+    }
+}
+
+impl Display for Nexthop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Default => Ok(()),
+            Self::Host {
+                host,
+                port,
+                mx_lookup,
+            } => {
+                if *mx_lookup {
+                    f.write_str(host)?;
+                } else {
+                    write!(f, "[{host}]")?;
+                }
+
+                if let Some(port) = port {
+                    write!(f, ":{port}")?;
+                }
+
+                Ok(())
+            }
+            Self::Socket(path) => write!(f, "unix:{path}"),
+            Self::Text(text) => f.write_str(text),
+        }
+    }
+}
+
+impl Display for TransportRoute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.transport, self.nexthop)
+    }
+}
+
+// transport(5): `pattern transport:nexthop`
+pub struct TransportFormat;
+
+impl MapFormat for TransportFormat {
+    fn syntax(&self) -> Syntax {
+        Syntax {
+            list_values: false,
+            ..Default::default()
+        }
+    }
+
+    fn value_label(&self) -> &'static str {
+        "Transporte"
+    }
+
+    // Patterns are addresses, domains, .domain (subdomains) or * (everything else)
+    fn validate_key(&self, key: &str) -> Result<(), String> {
+        if key == "*" {
+            return Ok(());
+        }
+
+        match key.strip_prefix('.').unwrap_or(key).parse::<Alias>() {
+            Ok(Alias::Address { .. } | Alias::Domain(_)) => Ok(()),
+            Ok(_) => Err(format!("'{key}' must be an address or a domain")),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    fn validate_value(&self, value: &str) -> Result<(), String> {
+        value.parse::<TransportRoute>().map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(value: &str) -> Result<TransportRoute, String> {
+        value.parse()
+    }
+
+    #[test]
+    fn nexthops_are_parsed() {
+        assert_eq!(parse("smtp:").unwrap().nexthop, Nexthop::Default);
+        assert_eq!(
+            parse("smtp:[mail.example.com]:587").unwrap().nexthop,
+            Nexthop::Host {
+                host: "mail.example.com".to_owned(),
+                port: Some(587),
+                mx_lookup: false,
+            }
+        );
+        assert_eq!(
+            parse("relay:example.com").unwrap().nexthop,
+            Nexthop::Host {
+                host: "example.com".to_owned(),
+                port: None,
+                mx_lookup: true,
+            }
+        );
+        assert_eq!(
+            parse("smtp:[192.0.2.1]").unwrap().nexthop,
+            Nexthop::Host {
+                host: "192.0.2.1".to_owned(),
+                port: None,
+                mx_lookup: false,
+            }
+        );
+        assert_eq!(
+            parse("lmtp:unix:/var/run/dovecot/lmtp").unwrap().nexthop,
+            Nexthop::Socket("/var/run/dovecot/lmtp".to_owned())
+        );
+        assert_eq!(
+            parse("error:5.1.1 mailbox unavailable").unwrap().nexthop,
+            Nexthop::Text("5.1.1 mailbox unavailable".to_owned())
+        );
+        assert_eq!(parse(":[relay.example.com]").unwrap().transport, "");
+    }
+
+    #[test]
+    fn invalid_routes_are_rejected() {
+        for value in [
+            "smtp",
+            "sm tp:example.com",
+            "smtp:[example.com",
+            "smtp:[example.com]587",
+            "smtp:example.com:0",
+            "smtp:example.com:99999",
+            "smtp:exa_mple.com",
+            "lmtp:unix:relative/socket",
+        ] {
+            assert!(parse(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn routes_render_back() {
+        for value in [
+            "smtp:",
+            "smtp:[mail.example.com]:587",
+            "relay:example.com:25",
+            "lmtp:unix:/var/run/dovecot/lmtp",
+            "discard:silently",
+        ] {
+            assert_eq!(parse(value).unwrap().to_string(), value);
+        }
+    }
+}
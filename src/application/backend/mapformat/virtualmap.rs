@@ -0,0 +1,44 @@
+use crate::application::backend::{
+    address::{Alias, Destination},
+    mapdocument::Syntax,
+};
+
+use super::MapFormat;
+
+// virtual(5): `alias destination, destination...`
+pub struct VirtualFormat;
+
+impl MapFormat for VirtualFormat {
+    fn syntax(&self) -> Syntax {
+        Syntax::default()
+    }
+
+    fn value_label(&self) -> &'static str {
+        "Redirecciones"
+    }
+
+    fn recursive(&self) -> bool {
+        true
+    }
+
+    // The full address first, then the catch-all for its domain
+    fn lookup_keys(&self, address: &str) -> Vec<String> {
+        match address.rsplit_once('@') {
+            Some((_, domain)) => vec![address.to_owned(), format!("@{domain}")],
+            None => vec![address.to_owned()],
+        }
+    }
+
+    fn validate_key(&self, key: &str) -> Result<(), String> {
+        key.parse::<Alias>()
+            .map(|_| ())
+            .map_err(|error| error.to_string())
+    }
+
+    fn validate_value(&self, value: &str) -> Result<(), String> {
+        value
+            .parse::<Destination>()
+            .map(|_| ())
+            .map_err(|error| error.to_string())
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet},
+    hash::{Hash, Hasher},
+};
+
+use super::mapdocument::MapDocument;
+
+// State of a remote map when it was downloaded, used to notice changes made by someone else before uploading
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    // Hash of the file contents
+    pub hash: u64,
+    // Modification time in seconds since the epoch, None if the server couldn't tell it
+    pub mtime: Option<i64>,
+    // The map as it was, the base when merging
+    pub document: MapDocument,
+}
+
+impl Snapshot {
+    pub fn new(data: &str, mtime: Option<i64>, document: MapDocument) -> Self {
+        Self {
+            hash: content_hash(data),
+            mtime,
+            document,
+        }
+    }
+}
+
+// Hash of a file's contents, only compared within the same run of the application
+pub fn content_hash(data: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+// The three versions of a map being merged
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Side {
+    // As it was downloaded
+    Base,
+    // As it is now on the server
+    Remote,
+    // As it was edited in the application
+    Local,
+}
+
+// An entry changed in different ways on the server and in the application
+#[derive(Clone, Debug)]
+pub struct Conflict {
+    pub key: String,
+    // Values of the entry on each side, None where it doesn't exist
+    pub base: Option<Vec<String>>,
+    pub remote: Option<Vec<String>>,
+    pub local: Option<Vec<String>>,
+}
+
+impl Conflict {
+    pub fn values(&self, side: Side) -> Option<&Vec<String>> {
+        match side {
+            Side::Base => self.base.as_ref(),
+            Side::Remote => self.remote.as_ref(),
+            Side::Local => self.local.as_ref(),
+        }
+    }
+}
+
+// A three-way merge of the local changes into the map found on the server
+#[derive(Clone, Debug)]
+pub struct Merge {
+    // The map on the server, the new base once the merge is applied
+    pub remote: Snapshot,
+    // The remote map with the local changes that don't conflict, conflicting entries keep the remote values until resolved
+    pub merged: MapDocument,
+    // Entries the user must choose a side for
+    pub conflicts: Vec<Conflict>,
+    // Number of entries changed on the server and not locally, taken as they are
+    pub remote_changes: usize,
+    // The rules of ordered tables can't be merged one by one, moving or adding a rule changes what the others do. The
+    // user keeps the remote map or overwrites it with the local one, which is what merged holds
+    pub whole_file: bool,
+}
+
+impl Merge {
+    // Merge the local changes made since base into remote
+    pub fn new(base: &MapDocument, remote: Snapshot, local: &MapDocument, ordered: bool) -> Self {
+        if ordered {
+            return Self {
+                remote,
+                merged: local.clone(),
+                conflicts: vec![],
+                remote_changes: 0,
+                whole_file: true,
+            };
+        }
+
+        let mut merged = remote.document.clone();
+        let mut conflicts = vec![];
+        let mut remote_changes = 0;
+
+        // Every key of the three documents, once, in the order they appear
+        let mut keys: Vec<&str> = vec![];
+        let mut seen: HashSet<&str> = HashSet::new();
+        for entry in base
+            .entries()
+            .chain(remote.document.entries())
+            .chain(local.entries())
+        {
+            if seen.insert(entry.key()) {
+                keys.push(entry.key());
+            }
+        }
+
+        for key in keys {
+            let base_values = base.get(key);
+            let remote_values = remote.document.get(key);
+            let local_values = local.get(key);
+
+            if local_values == base_values {
+                // Only the server may have changed it, merged already has its version
+                if remote_values != base_values {
+                    remote_changes += 1;
+                }
+            } else if remote_values == base_values {
+                // Only changed locally
+                apply(&mut merged, key, local_values);
+            } else if remote_values != local_values {
+                conflicts.push(Conflict {
+                    key: key.to_owned(),
+                    base: base_values.map(|values| values.to_vec()),
+                    remote: remote_values.map(|values| values.to_vec()),
+                    local: local_values.map(|values| values.to_vec()),
+                });
+            }
+        }
+
+        Self {
+            remote,
+            merged,
+            conflicts,
+            remote_changes,
+            whole_file: false,
+        }
+    }
+
+    // Keep one side of a conflict
+    pub fn resolve(&mut self, index: usize, side: Side) {
+        if index >= self.conflicts.len() {
+            return;
+        }
+
+        let conflict = self.conflicts.remove(index);
+        apply(
+            &mut self.merged,
+            &conflict.key,
+            conflict.values(side).map(|values| values.as_slice()),
+        );
+    }
+}
+
+// Set the values of a key, or remove it if it doesn't exist on the chosen side
+fn apply(document: &mut MapDocument, key: &str, values: Option<&[String]>) {
+    match values {
+        Some(values) => document.set(key, values.to_vec()),
+        None => document.remove(key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::backend::mapdocument::Syntax;
+
+    fn document(data: &str) -> MapDocument {
+        MapDocument::parse(data, Syntax::default()).unwrap()
+    }
+
+    fn snapshot(data: &str) -> Snapshot {
+        Snapshot::new(data, None, document(data))
+    }
+
+    const BASE: &str = "a x\nb y\nc z\n";
+
+    #[test]
+    fn changes_on_one_side_are_taken() {
+        let merge = Merge::new(
+            &document(BASE),
+            snapshot("a x\nb y2\nc z\n"),
+            &document("a x2\nb y\nc z\nd w\n"),
+            false,
+        );
+
+        assert!(merge.conflicts.is_empty());
+        assert_eq!(merge.remote_changes, 1);
+        assert_eq!(merge.merged.to_string(), "a x2\nb y2\nc z\nd w\n");
+    }
+
+    #[test]
+    fn same_change_on_both_sides_is_not_a_conflict() {
+        let merge = Merge::new(
+            &document(BASE),
+            snapshot("a x\nb y\n"),
+            &document("a x\nb y\n"),
+            false,
+        );
+
+        assert!(merge.conflicts.is_empty());
+        assert_eq!(merge.merged.to_string(), "a x\nb y\n");
+    }
+
+    #[test]
+    fn conflicts_are_resolved_by_side() {
+        let mut merge = Merge::new(
+            &document(BASE),
+            snapshot("a r\nb y\n"),
+            &document("a l\nb y\nc z2\n"),
+            false,
+        );
+
+        let keys: Vec<&str> = merge.conflicts.iter().map(|x| x.key.as_str()).collect();
+        assert_eq!(keys, ["a", "c"]);
+        assert_eq!(merge.conflicts[1].values(Side::Remote), None);
+
+        merge.resolve(0, Side::Local);
+        merge.resolve(0, Side::Base);
+        assert!(merge.conflicts.is_empty());
+        assert_eq!(merge.merged.to_string(), "a l\nb y\nc z\n");
+    }
+
+    #[test]
+    fn ordered_tables_are_not_merged() {
+        let local = document("c z\na x\nb y\n");
+        let merge = Merge::new(&document(BASE), snapshot("a x\nb y2\nc z\n"), &local, true);
+
+        assert!(merge.whole_file);
+        assert!(merge.conflicts.is_empty());
+        assert_eq!(merge.merged.to_string(), local.to_string());
+    }
+}
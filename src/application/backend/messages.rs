@@ -1,7 +1,12 @@
-use std::collections::HashMap;
-
-
-use super::server::Server;
+use super::{
+    backups::Backup,
+    hostkeys::HostKey,
+    lock::LockStatus,
+    mapdocument::{MapDocument, NormalizationReport},
+    merge::Snapshot,
+    remotecommand::StepResult,
+    server::{AuthStatus, HopStatus, Host, Server},
+};
 
 // Messages sent from the frontend to the backend
 pub enum QueryMessage {
@@ -13,9 +18,48 @@ pub enum QueryMessage {
     Authenticate {
         username: String,
         password: String,
+        // Passphrase for the private keys of servers using key authentication
+        passphrase: String,
         servers: Vec<Server>,
         root_password: String,
     },
+    // Add a host key the user accepted to the known hosts, for the maps waiting to log in with it
+    TrustHostKey {
+        servers: Vec<Server>,
+        host_key: HostKey,
+    },
+    // Take the lock of the map, the user started editing it
+    Lock(Server),
+    // Release the lock held by the application
+    Unlock(Server),
+    // Remove someone else's lock (a stale one) and take it
+    BreakLock(Server),
+    // List the backups of the map
+    ListBackups(Server),
+    // Download a backup to compare it with the map
+    DownloadBackup {
+        server: Server,
+        path: String,
+    },
+    // Delete the backups the retention policy doesn't keep
+    PruneBackups(Server),
+}
+
+impl QueryMessage {
+    // The map the message is about. Logins reach the workers one host at a time, this is its first map
+    pub fn server(&self) -> &Server {
+        match self {
+            Self::QueryVirtualUsers(server)
+            | Self::UpdateVirtualUsers(server)
+            | Self::Lock(server)
+            | Self::Unlock(server)
+            | Self::BreakLock(server)
+            | Self::ListBackups(server)
+            | Self::PruneBackups(server)
+            | Self::DownloadBackup { server, .. } => server,
+            Self::Authenticate { servers, .. } | Self::TrustHostKey { servers, .. } => &servers[0],
+        }
+    }
 }
 
 // Response messages sent from the backend to the frontend
@@ -23,7 +67,11 @@ pub enum ResponseMessage {
     // Got some virtual users
     GotVirtualUsers {
         server: Server,
-        users: HashMap<String, Vec<String>>,
+        users: MapDocument,
+        // Duplicated keys and destinations found in the map
+        report: NormalizationReport,
+        // Hash and mtime of the downloaded map, to notice changes made by someone else
+        snapshot: Snapshot,
     },
     // The virtual users query returned some errors
     QueryVirtualUsersResult {
@@ -34,6 +82,36 @@ pub enum ResponseMessage {
     ServerUploadResult {
         server: Server,
         error: Option<String>,
+        // The upload failed after installing the map, and the backup was installed again
+        rolled_back: bool,
+        // What every step run did, until the first failure
+        steps: Vec<StepResult>,
+        // The map on the server after a successful upload, the base for the next one
+        snapshot: Option<Snapshot>,
+    },
+    // The backups of the map, after listing or pruning them
+    Backups {
+        server: Server,
+        backups: Vec<Backup>,
+        error: Option<String>,
+    },
+    // A downloaded backup
+    BackupContent {
+        server: Server,
+        path: String,
+        document: Option<MapDocument>,
+        error: Option<String>,
+    },
+    // The lock of the map changed
+    LockResult {
+        server: Server,
+        lock: LockStatus,
+        error: Option<String>,
+    },
+    // The upload stopped because the map was changed on the server since it was downloaded
+    RemoteChanged {
+        server: Server,
+        remote: Snapshot,
     },
     // Result for the authentication process
     AuthenticationResult {
@@ -41,4 +119,25 @@ pub enum ResponseMessage {
         success: bool,
         error: Option<String>,
     },
+    // The server presented a host key that isn't known yet, the user must confirm it before authenticating
+    UnknownHostKey {
+        server: Server,
+        host_key: HostKey,
+    },
+    // The host key was stored (or couldn't be), the maps waiting for it can log in again
+    HostKeyTrusted {
+        servers: Vec<Server>,
+        error: Option<String>,
+    },
+    // The session of a host was found dead and logged in again, Reconnecting while it happens
+    ConnectionStatus {
+        host: Host,
+        status: AuthStatus,
+        error: Option<String>,
+    },
+    // Progress of a login through jump hosts
+    HopsStatus {
+        host: Host,
+        hops: Vec<HopStatus>,
+    },
 }
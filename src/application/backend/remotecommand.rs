@@ -0,0 +1,166 @@
+use std::fmt::{self, Display};
+
+use super::merge::Snapshot;
+
+// What a command run on the server printed, and how it finished
+#[derive(Clone, Debug, Default)]
+pub struct CommandOutput {
+    // The command, without the escalation wrapper
+    pub command: String,
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_status: i32,
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.exit_status == 0
+    }
+
+    // The output most likely to explain a failure: stderr, or stdout for commands run in a terminal
+    pub fn message(&self) -> &str {
+        if self.stderr.trim().is_empty() {
+            self.stdout.trim()
+        } else {
+            self.stderr.trim()
+        }
+    }
+}
+
+// The steps of an upload, in the order they run
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UploadStep {
+    // Make sure the session works, logging in again if it died
+    Connect,
+    // Check the entries with the map format
+    Validate,
+    // Check the map wasn't changed on the server since it was downloaded
+    Compare,
+    // Copy the live map to the user's home
+    Backup,
+    // Send the new map to the server
+    Transfer,
+    // Build a temporary copy of the new map and run postfix check, proving postfix can read it
+    Preflight,
+    // Replace the live map as root
+    Install,
+    // Download the installed map and compare it with what was sent
+    Verify,
+    // One of the post-install commands, by its position in the server's list
+    Hook(usize),
+    // Install the backup again and rerun the post-install commands, after a failure once the map was installed
+    Rollback,
+}
+
+impl Display for UploadStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Connect => "connect",
+            Self::Validate => "validate",
+            Self::Compare => "compare",
+            Self::Backup => "backup",
+            Self::Transfer => "transfer",
+            Self::Preflight => "preflight",
+            Self::Install => "install",
+            Self::Verify => "verify",
+            Self::Hook(_) => "post-install hook",
+            Self::Rollback => "rollback",
+        })
+    }
+}
+
+// How an upload step went
+#[derive(Clone, Debug)]
+pub struct StepResult {
+    pub step: UploadStep,
+    // The command run by the step, steps that don't run commands have none
+    pub output: Option<CommandOutput>,
+    // Why the step failed
+    pub error: Option<String>,
+}
+
+impl StepResult {
+    // A step that finished without running any command
+    pub fn done(step: UploadStep) -> Self {
+        Self {
+            step,
+            output: None,
+            error: None,
+        }
+    }
+
+    // A step that couldn't finish
+    pub fn failed(step: UploadStep, error: impl Display) -> Self {
+        Self {
+            step,
+            output: None,
+            error: Some(error.to_string()),
+        }
+    }
+
+    // A step that ran a command, it fails with the command's exit status
+    pub fn from_output(step: UploadStep, output: CommandOutput) -> Self {
+        let error = (!output.success()).then(|| {
+            format!(
+                "'{}' failed with exit status {}: {}",
+                output.command,
+                output.exit_status,
+                output.message()
+            )
+        });
+
+        Self {
+            step,
+            output: Some(output),
+            error,
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+impl Display for StepResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.error {
+            Some(error) => write!(f, "{} failed: {error}", self.step),
+            None => write!(f, "{} done", self.step),
+        }
+    }
+}
+
+// What an upload did
+pub struct UploadOutcome {
+    pub steps: Vec<StepResult>,
+    // The map left on the server by a successful upload
+    pub installed: Option<Snapshot>,
+    // The map found on the server when the upload stopped because someone else changed it
+    pub remote_changed: Option<Snapshot>,
+}
+
+// An upload and the steps it ran, until the first failure
+#[derive(Clone, Debug)]
+pub struct Operation {
+    // Local time the result arrived, HH:MM:SS
+    pub time: String,
+    pub steps: Vec<StepResult>,
+}
+
+impl Operation {
+    pub fn success(&self) -> bool {
+        self.steps.iter().all(|step| step.success())
+    }
+
+    // Whether the previous map was installed again after a failure
+    pub fn rolled_back(&self) -> bool {
+        rolled_back(&self.steps)
+    }
+}
+
+// Whether the steps of an upload end with a successful rollback
+pub fn rolled_back(steps: &[StepResult]) -> bool {
+    steps
+        .last()
+        .is_some_and(|step| step.step == UploadStep::Rollback && step.success())
+}
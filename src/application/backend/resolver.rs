@@ -0,0 +1,268 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Display},
+};
+
+use super::{
+    mapdocument::MapDocument,
+    mapformat::{MapFormat, ValidationError},
+};
+
+// Chains with more redirections than this are reported, long before postfix gives up (virtual_alias_recursion_limit)
+pub const MAX_DEPTH: usize = 10;
+// Same limit postfix uses for the number of addresses in an expansion (virtual_alias_expansion_limit)
+pub const MAX_ADDRESSES: usize = 1000;
+
+// Something that would make postfix bounce or defer the mail for an address
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExpansionProblem {
+    // The address expands back to one of the addresses it came from, the path goes from the repeated address to itself
+    Loop(Vec<String>),
+    // The chain of redirections is longer than MAX_DEPTH
+    TooDeep(Vec<String>),
+    // The expansion has more than MAX_ADDRESSES addresses
+    TooLarge,
+}
+
+impl Display for ExpansionProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Loop(path) => write!(f, "forwarding loop: {}", path.join(" → ")),
+            Self::TooDeep(path) => write!(
+                f,
+                "more than {MAX_DEPTH} levels of redirections: {} → ...",
+                path.join(" → ")
+            ),
+            Self::TooLarge => write!(f, "more than {MAX_ADDRESSES} addresses in the expansion"),
+        }
+    }
+}
+
+// An address and what it expands to
+#[derive(Clone, Debug)]
+pub struct Expansion {
+    pub address: String,
+    // Key of the entry used to expand the address, None for final recipients
+    pub key: Option<String>,
+    pub children: Vec<Expansion>,
+    // Why the expansion stopped at this address, if it did
+    pub problem: Option<ExpansionProblem>,
+}
+
+impl Expansion {
+    // Addresses the mail is finally delivered to, without repetitions
+    pub fn recipients(&self) -> Vec<String> {
+        let mut recipients: Vec<String> = vec![];
+        self.visit(&mut |node| {
+            if node.key.is_none()
+                && node.problem.is_none()
+                && !recipients
+                    .iter()
+                    .any(|x| x.eq_ignore_ascii_case(&node.address))
+            {
+                recipients.push(node.address.clone());
+            }
+        });
+        recipients
+    }
+
+    // Every problem found in the expansion
+    pub fn problems(&self) -> Vec<ExpansionProblem> {
+        let mut problems = vec![];
+        self.visit(&mut |node| {
+            if let Some(problem) = &node.problem {
+                if !problems.contains(problem) {
+                    problems.push(problem.clone());
+                }
+            }
+        });
+        problems
+    }
+
+    fn visit(&self, action: &mut impl FnMut(&Expansion)) {
+        action(self);
+        for child in self.children.iter() {
+            child.visit(action);
+        }
+    }
+}
+
+// Expands addresses through the entries of a table the same way postfix does, recursively
+pub struct Resolver<'a, F: MapFormat + ?Sized> {
+    format: &'a F,
+    // Values for every key, lowercase because lookups are case-insensitive. Postmap keeps the first of duplicated keys
+    entries: HashMap<String, (&'a str, &'a [String])>,
+    // Addresses found so far in the current expansion
+    addresses: usize,
+}
+
+impl<'a, F: MapFormat + ?Sized> Resolver<'a, F> {
+    pub fn new(format: &'a F, document: &'a MapDocument) -> Self {
+        let mut entries = HashMap::new();
+        for entry in document.entries() {
+            entries
+                .entry(entry.key().to_lowercase())
+                .or_insert((entry.key(), entry.values()));
+        }
+
+        Self {
+            format,
+            entries,
+            addresses: 0,
+        }
+    }
+
+    // Expand an address to its final recipients
+    pub fn expand(&mut self, address: &str) -> Expansion {
+        self.addresses = 0;
+        self.expand_path(address, &mut vec![])
+    }
+
+    fn expand_path(&mut self, address: &str, path: &mut Vec<String>) -> Expansion {
+        let mut node = Expansion {
+            address: address.to_owned(),
+            key: None,
+            children: vec![],
+            problem: None,
+        };
+
+        self.addresses += 1;
+        if self.addresses > MAX_ADDRESSES {
+            node.problem = Some(ExpansionProblem::TooLarge);
+            return node;
+        }
+
+        // The first key found for the address is the one postfix uses
+        let Some((key, values)) = self
+            .format
+            .lookup_keys(address)
+            .into_iter()
+            .find_map(|key| self.entries.get(&key.to_lowercase()).copied())
+        else {
+            return node;
+        };
+
+        // Coming back to an address that is already being expanded never ends
+        if let Some(start) = path.iter().position(|x| x.eq_ignore_ascii_case(address)) {
+            let mut cycle = path[start..].to_vec();
+            cycle.push(address.to_owned());
+            node.problem = Some(ExpansionProblem::Loop(cycle));
+            return node;
+        }
+
+        if path.len() >= MAX_DEPTH {
+            let mut chain = path.clone();
+            chain.push(address.to_owned());
+            node.problem = Some(ExpansionProblem::TooDeep(chain));
+            return node;
+        }
+
+        node.key = Some(key.to_owned());
+        path.push(address.to_owned());
+        for value in values {
+            // An address that lists itself gets the mail too, without being expanded again
+            node.children.push(if value.eq_ignore_ascii_case(address) {
+                Expansion {
+                    address: value.clone(),
+                    key: None,
+                    children: vec![],
+                    problem: None,
+                }
+            } else {
+                self.expand_path(value, path)
+            });
+        }
+        path.pop();
+
+        node
+    }
+}
+
+// Expand every key of a table, reporting loops and chains postfix wouldn't deliver
+pub fn check<F: MapFormat + ?Sized>(format: &F, document: &MapDocument) -> Vec<ValidationError> {
+    let mut resolver = Resolver::new(format, document);
+    let mut errors = vec![];
+    // Duplicated keys expand the same way, they are checked once
+    let mut checked = HashSet::new();
+
+    for entry in document.entries() {
+        if !checked.insert(entry.key().to_lowercase()) {
+            continue;
+        }
+
+        for problem in resolver.expand(entry.key()).problems() {
+            errors.push(ValidationError {
+                key: entry.key().to_owned(),
+                message: problem.to_string(),
+            });
+        }
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::backend::mapformat::virtualmap::VirtualFormat;
+
+    fn document(data: &str) -> MapDocument {
+        VirtualFormat.parse(data).unwrap()
+    }
+
+    fn messages(data: &str) -> Vec<String> {
+        check(&VirtualFormat, &document(data))
+            .into_iter()
+            .map(|error| error.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn addresses_expand_to_final_recipients() {
+        let document = document(
+            "a@example.com b@example.com, C@example.com\n\
+             b@example.com d@example.org\n\
+             c@example.com d@example.org, c@example.com\n\
+             @example.com catchall@example.org\n",
+        );
+        let mut resolver = Resolver::new(&VirtualFormat, &document);
+
+        let expansion = resolver.expand("a@example.com");
+        assert!(expansion.problems().is_empty());
+        assert_eq!(expansion.recipients(), ["d@example.org", "c@example.com"]);
+
+        let expansion = resolver.expand("other@example.com");
+        assert_eq!(expansion.key.as_deref(), Some("@example.com"));
+        assert_eq!(expansion.recipients(), ["catchall@example.org"]);
+    }
+
+    #[test]
+    fn loops_are_reported() {
+        assert!(messages("a@example.com b@example.com\nb@example.com x@example.org\n").is_empty());
+        assert_eq!(
+            messages("a@example.com b@example.com\nb@example.com a@example.com\n"),
+            [
+                "a@example.com: forwarding loop: a@example.com → b@example.com → a@example.com",
+                "b@example.com: forwarding loop: b@example.com → a@example.com → b@example.com",
+            ]
+        );
+        // Keys are looked up whatever the case
+        assert_eq!(
+            messages("a@example.com B@example.com\nb@example.com A@example.com\n").len(),
+            2
+        );
+        // A catch-all that points back into its domain delivers to the address itself
+        assert!(messages("@example.com A@example.com\n").is_empty());
+    }
+
+    #[test]
+    fn long_chains_are_reported() {
+        let data: String = (0..=MAX_DEPTH)
+            .map(|index| format!("a{index}@example.com a{}@example.com\n", index + 1))
+            .collect();
+
+        let errors = messages(&data);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("a0@example.com: more than 10 levels"));
+    }
+}
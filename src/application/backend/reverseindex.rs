@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+
+use super::mapdocument::MapDocument;
+
+// Form of a destination used to compare them, so the different ways of writing it are found ("bob", \bob, BOB)
+pub fn normalize_destination(value: &str) -> String {
+    let value = value.trim().trim_matches('"');
+    value.strip_prefix('\\').unwrap_or(value).to_lowercase()
+}
+
+// The keys that list every destination of a map
+pub struct ReverseIndex<'a> {
+    forwarders: HashMap<String, Vec<&'a str>>,
+}
+
+impl<'a> ReverseIndex<'a> {
+    pub fn new(document: &'a MapDocument) -> Self {
+        let mut forwarders: HashMap<String, Vec<&'a str>> = HashMap::new();
+
+        for entry in document.entries() {
+            for value in entry.values() {
+                let keys = forwarders.entry(normalize_destination(value)).or_default();
+                if !keys.contains(&entry.key()) {
+                    keys.push(entry.key());
+                }
+            }
+        }
+
+        Self { forwarders }
+    }
+
+    // Keys of the entries that list a destination, in file order
+    pub fn forwarders(&self, destination: &str) -> &[&'a str] {
+        self.forwarders
+            .get(&normalize_destination(destination))
+            .map(|keys| keys.as_slice())
+            .unwrap_or_default()
+    }
+}
+
+// Values of an entry without a destination, None if there is no entry for the key
+fn remaining_values(document: &MapDocument, key: &str, destination: &str) -> Option<Vec<String>> {
+    let destination = normalize_destination(destination);
+    document
+        .entries()
+        .find(|entry| entry.key() == key)
+        .map(|entry| {
+            entry
+                .values()
+                .iter()
+                .filter(|value| normalize_destination(value) != destination)
+                .cloned()
+                .collect()
+        })
+}
+
+// Whether removing a destination from an entry leaves it empty, so the whole entry would be removed
+pub fn removes_entry(document: &MapDocument, key: &str, destination: &str) -> bool {
+    remaining_values(document, key, destination).is_some_and(|values| values.is_empty())
+}
+
+// Remove a destination from an entry, the entry is removed too if it's left without destinations
+pub fn remove_destination(document: &mut MapDocument, key: &str, destination: &str) {
+    let Some(values) = remaining_values(document, key, destination) else {
+        return;
+    };
+
+    if values.is_empty() {
+        document.remove(key);
+    } else {
+        document.set(key, values);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::backend::mapdocument::Syntax;
+
+    fn document(data: &str) -> MapDocument {
+        MapDocument::parse(data, Syntax::default()).unwrap()
+    }
+
+    #[test]
+    fn forwarders_are_found_however_the_destination_is_written() {
+        let document = document(
+            "a@example.com bob, x@example.org\n\
+             b@example.com \\Bob\n\
+             c@example.com \"bob\", bob\n\
+             d@example.com y@example.org\n",
+        );
+        let index = ReverseIndex::new(&document);
+
+        assert_eq!(
+            index.forwarders("BOB"),
+            ["a@example.com", "b@example.com", "c@example.com"]
+        );
+        assert!(index.forwarders("nobody").is_empty());
+    }
+
+    #[test]
+    fn removing_the_last_destination_removes_the_entry() {
+        let mut document =
+            document("# aliases\na@example.com bob, x@example.org\nb@example.com Bob\n");
+
+        assert!(!removes_entry(&document, "a@example.com", "bob"));
+        assert!(removes_entry(&document, "b@example.com", "bob"));
+        assert!(!removes_entry(&document, "missing@example.com", "bob"));
+
+        remove_destination(&mut document, "a@example.com", "bob");
+        remove_destination(&mut document, "b@example.com", "bob");
+        remove_destination(&mut document, "missing@example.com", "bob");
+        assert_eq!(
+            document.to_string(),
+            "# aliases\na@example.com x@example.org\n"
+        );
+    }
+}
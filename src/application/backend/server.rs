@@ -1,22 +1,27 @@
-use std::{
-    collections::HashMap,
-    fmt::{self, Display},
-};
+use std::fmt::{self, Display};
 
 use serde::Deserialize;
 
-#[derive(Clone, Debug, PartialEq)]
+use super::{
+    backups::{Backup, BackupView},
+    lock::LockStatus,
+    mapdocument::{MapDocument, NormalizationReport},
+    mapformat::{MapKind, ValidationError},
+    merge::{Merge, Snapshot},
+    remotecommand::Operation,
+    sshconfig::{HostConfig, JumpSpec, SshConfig},
+    sshwrapper,
+};
+
+#[derive(Clone, Debug, Default, PartialEq)]
 pub enum AuthStatus {
+    #[default]
     Unknown,
     Failed,
     Authenticated,
     InProgress,
-}
-
-impl Default for AuthStatus {
-    fn default() -> Self {
-        Self::Unknown
-    }
+    // The session died and is being logged in again with the stored credentials
+    Reconnecting,
 }
 
 impl From<bool> for AuthStatus {
@@ -29,41 +34,322 @@ impl From<bool> for AuthStatus {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+// How the ssh session authenticates with a server
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    // The password typed in the login screen
+    #[default]
+    Password,
+    // A private key file, optionally protected with the passphrase typed in the login screen
+    Key,
+    // The keys served by a running ssh-agent (SSH_AUTH_SOCK)
+    Agent,
+}
+
+// How the upload gets root privileges to replace the map and run post_update
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Escalation {
+    // su root -c, asks for the root password typed in the login screen
+    #[default]
+    Su,
+    // sudo -S, asks for the user's own password
+    Sudo,
+    // doas, asks for the user's own password
+    Doas,
+    // The user already is root, no escalation needed
+    Root,
+}
+
+impl Display for Escalation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Su => "su",
+            Self::Sudo => "sudo",
+            Self::Doas => "doas",
+            Self::Root => "root",
+        })
+    }
+}
+
+// A host the session goes through to reach a server, with its own credentials
+#[derive(Clone, Debug, Deserialize)]
+pub struct JumpHost {
+    // Address of the jump host, or a Host alias of ~/.ssh/config
+    pub addr: String,
+    // Port of its ssh server, the ssh config's or 22 when not set
+    #[serde(default)]
+    pub port: Option<u16>,
+    // User on the jump host, the ssh config's or the login username when not set
+    #[serde(default)]
+    pub username: Option<String>,
+    // How to authenticate with it, password by default (the login password and passphrase are used)
+    #[serde(default)]
+    pub auth: Option<AuthMethod>,
+    // Private key for key authentication
+    #[serde(default)]
+    pub identity_file: Option<String>,
+    // Another jump host in front of this one
+    #[serde(default)]
+    pub jump: Option<Box<JumpHost>>,
+
+    // HostName of addr in the ssh config, the address actually connected to
+    #[serde(skip)]
+    pub host_name: Option<String>,
+}
+
+impl JumpHost {
+    // A jump host from a ProxyJump destination, the hosts before it in the list are its own jump hosts
+    fn from_spec(spec: JumpSpec, jump: Option<Box<JumpHost>>) -> Self {
+        Self {
+            addr: spec.host,
+            port: spec.port,
+            username: spec.user,
+            auth: None,
+            identity_file: None,
+            jump,
+            host_name: None,
+        }
+    }
+
+    // Fill what isn't set with the ssh config of addr and of the jump hosts in front of it. Their ProxyJump isn't followed,
+    // the chain is the one configured for the server
+    fn resolve(&mut self, config: &SshConfig) {
+        resolve_login(
+            &config.host(&self.addr),
+            &mut self.host_name,
+            &mut self.port,
+            &mut self.username,
+            &mut self.auth,
+            &mut self.identity_file,
+        );
+
+        if let Some(jump) = &mut self.jump {
+            jump.resolve(config);
+        }
+    }
+
+    // Logins needed to reach addr, the outermost jump host first and addr itself last
+    fn hops(&self, username: &str) -> Vec<Hop> {
+        let mut hops = self
+            .jump
+            .as_ref()
+            .map(|jump| jump.hops(username))
+            .unwrap_or_default();
+        hops.push(Hop {
+            addr: self.host_name.clone().unwrap_or(self.addr.clone()),
+            port: self.port.unwrap_or(DEFAULT_PORT),
+            username: self.username.clone().unwrap_or(username.to_owned()),
+            auth: self.auth.unwrap_or_default(),
+            identity_file: self.identity_file.clone(),
+        });
+
+        hops
+    }
+}
+
+// Fill the login settings config.toml leaves unset, of a server or a jump host, with the ssh config of its address.
+// A key is used when the ssh config sets an IdentityFile: only one key is tried, the first of them found on this
+// machine, as ssh would skip the missing ones
+fn resolve_login(
+    host: &HostConfig,
+    host_name: &mut Option<String>,
+    port: &mut Option<u16>,
+    username: &mut Option<String>,
+    auth: &mut Option<AuthMethod>,
+    identity_file: &mut Option<String>,
+) {
+    *host_name = host.host_name.clone();
+    *port = port.or(host.port);
+    *username = username.take().or(host.user.clone());
+    let found = host
+        .identity_files
+        .iter()
+        .find(|path| sshwrapper::identity_file(Some(path)).is_some_and(|path| path.exists()))
+        .or(host.identity_files.first());
+    *auth = auth.or(found.map(|_| AuthMethod::Key));
+    *identity_file = identity_file.take().or(found.cloned());
+}
+
+// Port of the ssh servers when neither config.toml nor the ssh config set one
+const DEFAULT_PORT: u16 = 22;
+
+// One of the ssh logins needed to reach a server: its jump hosts, then the server itself
+#[derive(Clone, Debug)]
+pub struct Hop {
+    pub addr: String,
+    pub port: u16,
+    pub username: String,
+    pub auth: AuthMethod,
+    pub identity_file: Option<String>,
+}
+
+impl Display for Hop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}:{}", self.username, self.addr, self.port)
+    }
+}
+
+// Authentication status of one hop, shown in the login screen
+#[derive(Clone, Debug)]
+pub struct HopStatus {
+    pub name: String,
+    pub status: AuthStatus,
+}
+
+// How to reach and log into the host of a map. Maps configured on the same host share one session, made with the
+// settings of the first of them
+#[derive(Clone, Debug)]
+pub struct Host {
+    // Address as configured, maybe a Host alias of the ssh config
+    pub addr: String,
+    // HostName of addr in the ssh config
+    pub host_name: Option<String>,
+    pub port: u16,
+    // User on the host, the login username when not set
+    pub username: Option<String>,
+    pub auth: AuthMethod,
+    pub identity_file: Option<String>,
+    pub jump: Option<JumpHost>,
+    // How commands become root on the host
+    pub escalation: Escalation,
+}
+
+impl Host {
+    // Logins needed to reach the host, in the order they are made: the outermost jump host first, the host last
+    pub fn hops(&self, username: &str) -> Vec<Hop> {
+        // The host is the last login of a chain of jump hosts
+        JumpHost {
+            addr: self.addr.clone(),
+            port: Some(self.port),
+            username: self.username.clone(),
+            auth: Some(self.auth),
+            identity_file: self.identity_file.clone(),
+            jump: self.jump.clone().map(Box::new),
+            host_name: self.host_name.clone(),
+        }
+        .hops(username)
+    }
+}
+
+// Identity of the host, maps with the same one are on the same host
+impl Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.username {
+            Some(username) => write!(f, "{username}@{}:{}", self.addr, self.port),
+            None => write!(f, "{}:{}", self.addr, self.port),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
 pub enum UsersStatus {
+    #[default]
     Unknown,
     Downloading,
     Idle,
     Uploading,
 }
 
-impl Default for UsersStatus {
-    fn default() -> Self {
-        Self::Unknown
-    }
-}
-
 // Struct to hold information about the servers and throw it around between threads
 #[derive(Clone, Deserialize)]
 pub struct Server {
     // Path for the configuration on the server (tipically /etc/postfix/virtual or .../virtualuser)
     pub config_path: String,
-    // Addres of the server (ip or dns, it resolves autimatically)
+    // Addres of the server (ip or dns, it resolves autimatically), or a Host alias of ~/.ssh/config
     pub addr: String,
-    // Port for the ssh connection, tipically 22
-    pub port: u16,
+    // Port for the ssh connection, the ssh config's or 22 when not set
+    #[serde(default)]
+    pub port: Option<u16>,
+    // User on the server, the ssh config's or the login username when not set
+    #[serde(default)]
+    pub username: Option<String>,
+    // Kind of lookup table stored in config_path, virtual by default
+    #[serde(default)]
+    pub format: MapKind,
+    // How to authenticate, a key when the ssh config sets an IdentityFile and password otherwise
+    #[serde(default)]
+    pub auth: Option<AuthMethod>,
+    // Private key for key authentication, the usual ~/.ssh/id_* files are tried when it's not set
+    #[serde(default)]
+    pub identity_file: Option<String>,
+    // How to become root when uploading, su by default
+    #[serde(default)]
+    pub escalation: Escalation,
+    // Commands run as root after installing the map, the format's defaults (postmap, postfix reload) when not set
+    #[serde(default)]
+    pub post_install: Option<Vec<String>>,
+    // Host the ssh session is tunneled through, the ssh config's ProxyJump or a direct connection when not set
+    #[serde(default)]
+    pub jump: Option<JumpHost>,
+
+    // HostName of addr in the ssh config, the address actually connected to
+    #[serde(skip)]
+    pub host_name: Option<String>,
+    // Number of backups of the map kept in the user's home, all of them when not set
+    #[serde(default)]
+    pub keep_backups: Option<usize>,
+    // Days a backup is kept, forever when not set
+    #[serde(default)]
+    pub keep_backups_days: Option<u32>,
+
+    // Store the loaded data from the server, it's not serialized so it must be skipped.
+    // Changes go through users_mut or set_users, so the validation is done again
+    #[serde(skip)]
+    pub users: MapDocument,
+
+    // Problems the format found in users, None until they are needed after a change
+    #[serde(skip)]
+    validation: Option<Vec<ValidationError>>,
+
+    // The map as it was downloaded, uploads are refused if the server's one changed since then
+    #[serde(skip)]
+    pub snapshot: Snapshot,
+
+    // Backups of the map on the server, None until they are listed
+    #[serde(skip)]
+    pub backups: Option<Vec<Backup>>,
+
+    // Backup being compared with the map
+    #[serde(skip)]
+    pub backup_view: Option<BackupView>,
+
+    // Backup being uploaded, it replaces users only once the upload succeeds so local edits survive a failure
+    #[serde(skip)]
+    pub restoring: Option<MapDocument>,
+
+    // Advisory lock of the map on the server, taken when the user starts editing it
+    #[serde(skip)]
+    pub lock: LockStatus,
+
+    // Merge of the local changes with a map changed on the server, until the user applies it
+    #[serde(skip)]
+    pub merge: Option<Merge>,
 
-    // Store the loaded data from the server, it's not serialized so it must be skipped
+    // Duplicated keys and destinations found when the map was loaded, cleared when the user merges or dismisses it
     #[serde(skip)]
-    pub users: HashMap<String, Vec<String>>,
+    pub normalization: NormalizationReport,
 
     // Authentication status
     #[serde(skip)]
     pub auth_status: AuthStatus,
 
+    // Why the last authentication failed, shown in the login screen
+    #[serde(skip)]
+    pub auth_error: Option<String>,
+
+    // Authentication status of every hop of the last login, when the server is behind jump hosts
+    #[serde(skip)]
+    pub hops: Vec<HopStatus>,
+
     // Authentication status
     #[serde(skip)]
     pub users_status: UsersStatus,
+
+    // Uploads done since the application started, shown in the operation log
+    #[serde(skip)]
+    pub operations: Vec<Operation>,
 }
 
 // Compare two server instances, only taking into account the path, address and port
@@ -76,28 +362,259 @@ impl PartialEq for Server {
 impl Server {
     // Generate the payload to be uploaded to the server, based on the user-defined info
     pub fn payload(&self) -> String {
-        let mut payload = String::new();
-        for (k, entries) in self.users.iter() {
-            payload += &format!("{k} {}\n", entries.join(" "));
+        self.format.format().serialize(&self.users)
+    }
+
+    // Get the map for changing it, its validation is outdated from now on
+    pub fn users_mut(&mut self) -> &mut MapDocument {
+        self.validation = None;
+        &mut self.users
+    }
+
+    // Replace the whole map
+    pub fn set_users(&mut self, users: MapDocument) {
+        *self.users_mut() = users;
+    }
+
+    // Entries the format considers invalid. Validating follows every alias, so it's only done after the map changes
+    pub fn validation_errors(&mut self) -> &[ValidationError] {
+        self.validation
+            .get_or_insert_with(|| self.format.format().validate(&self.users))
+    }
+
+    // Commands to run after installing the map
+    pub fn post_install_commands(&self) -> Vec<String> {
+        self.post_install
+            .clone()
+            .unwrap_or_else(|| self.format.format().post_install(&self.config_path))
+    }
+
+    // Fill what config.toml doesn't set with the ssh config of addr: HostName, Port, User, IdentityFile and ProxyJump
+    pub fn resolve(&mut self, config: &SshConfig) {
+        let host = config.host(&self.addr);
+        resolve_login(
+            &host,
+            &mut self.host_name,
+            &mut self.port,
+            &mut self.username,
+            &mut self.auth,
+            &mut self.identity_file,
+        );
+
+        if self.jump.is_none() {
+            // The first host of the list is the outermost one
+            self.jump = host
+                .proxy_jump
+                .map(|value| JumpSpec::parse_list(&value))
+                .unwrap_or_default()
+                .into_iter()
+                .fold(None, |jump, spec| {
+                    Some(JumpHost::from_spec(spec, jump.map(Box::new)))
+                });
         }
+        if let Some(jump) = &mut self.jump {
+            jump.resolve(config);
+        }
+    }
+
+    // Name of the map shown in the interface, its file name
+    pub fn map_name(&self) -> String {
+        std::path::Path::new(&self.config_path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or(self.config_path.clone())
+    }
 
-        payload
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(DEFAULT_PORT)
     }
 
-    pub fn to_string_extended(&self) -> String {
-        format!("{}:{}:{}", self.addr, self.port, self.config_path)
+    // The host the map is on, every map of the same host shares its session
+    pub fn host(&self) -> Host {
+        Host {
+            addr: self.addr.clone(),
+            host_name: self.host_name.clone(),
+            port: self.port(),
+            username: self.username.clone(),
+            auth: self.auth.unwrap_or_default(),
+            identity_file: self.identity_file.clone(),
+            jump: self.jump.clone(),
+            escalation: self.escalation,
+        }
     }
 
     pub fn busy(&self) -> bool {
         self.auth_status == AuthStatus::InProgress
+            || self.auth_status == AuthStatus::Reconnecting
             || self.users_status == UsersStatus::Downloading
             || self.users_status == UsersStatus::Uploading
     }
 }
 
+// The hosts of the maps in the order they are configured, with the positions of their maps. Maps with the same address,
+// port and username are on the same host, logged into with the settings of the first of them
+pub fn hosts(servers: &[Server]) -> Vec<(Host, Vec<usize>)> {
+    let mut hosts: Vec<(Host, Vec<usize>)> = vec![];
+    for (index, server) in servers.iter().enumerate() {
+        let host = server.host();
+        match hosts
+            .iter_mut()
+            .find(|(known, _)| known.to_string() == host.to_string())
+        {
+            Some((_, maps)) => maps.push(index),
+            None => hosts.push((host, vec![index])),
+        }
+    }
+    hosts
+}
+
 // Simple way to represent the server for debuging reasons mainly
 impl Display for Server {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str(&format!("{}:{}", self.addr, self.port))
+        f.write_str(&format!("{}:{}", self.addr, self.port()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::{
+        providers::{Format, Toml},
+        Figment,
+    };
+
+    use super::*;
+
+    // Servers as config.toml lists them
+    fn servers(text: &str) -> Vec<Server> {
+        #[derive(Deserialize)]
+        struct Servers {
+            servers: Vec<Server>,
+        }
+
+        Figment::from(Toml::string(text))
+            .extract::<Servers>()
+            .unwrap()
+            .servers
+    }
+
+    fn names(hops: &[Hop]) -> Vec<String> {
+        hops.iter().map(|hop| hop.to_string()).collect()
+    }
+
+    #[test]
+    fn hops_start_with_the_outermost_jump_host() {
+        let mut server = servers(
+            r#"
+[[servers]]
+addr = "mx"
+config_path = "/etc/postfix/virtual"
+username = "postmaster"
+
+[servers.jump]
+addr = "inner"
+auth = "key"
+identity_file = "~/.ssh/id_inner"
+
+[servers.jump.jump]
+addr = "outer"
+port = 2200
+username = "admin"
+"#,
+        )
+        .remove(0);
+        server.resolve(&SshConfig::parse("Host mx\n    HostName 10.0.0.1\n"));
+
+        let hops = server.host().hops("jdoe");
+        // Ports default to 22 and usernames to the login one
+        assert_eq!(
+            names(&hops),
+            [
+                "admin@outer:2200",
+                "jdoe@inner:22",
+                "postmaster@10.0.0.1:22"
+            ]
+        );
+        assert_eq!(
+            hops.iter().map(|hop| hop.auth).collect::<Vec<_>>(),
+            [AuthMethod::Password, AuthMethod::Key, AuthMethod::Password]
+        );
+        assert_eq!(hops[1].identity_file.as_deref(), Some("~/.ssh/id_inner"));
+    }
+
+    #[test]
+    fn proxy_jump_lists_become_nested_jump_hosts() {
+        let mut server = servers(
+            r#"
+[[servers]]
+addr = "mx"
+config_path = "/etc/postfix/virtual"
+"#,
+        )
+        .remove(0);
+        server.resolve(&SshConfig::parse(
+            "Host mx\n    Port 2222\n    ProxyJump first,admin@second:2200\n\nHost second\n    HostName 192.0.2.2\n",
+        ));
+
+        // The first host of the list is the outermost one, and the jump hosts get their own ssh config
+        assert_eq!(
+            names(&server.host().hops("jdoe")),
+            ["jdoe@first:22", "admin@192.0.2.2:2200", "jdoe@mx:2222"]
+        );
+
+        // A jump set in config.toml wins over the ProxyJump
+        let mut server = servers(
+            r#"
+[[servers]]
+addr = "mx"
+config_path = "/etc/postfix/virtual"
+jump = { addr = "bastion" }
+"#,
+        )
+        .remove(0);
+        server.resolve(&SshConfig::parse("Host mx\n    ProxyJump first\n"));
+        assert_eq!(
+            names(&server.host().hops("jdoe")),
+            ["jdoe@bastion:22", "jdoe@mx:22"]
+        );
+    }
+
+    #[test]
+    fn maps_are_grouped_by_address_port_and_username() {
+        let servers = servers(
+            r#"
+servers = [
+    { addr = "mx", config_path = "/etc/postfix/virtual", auth = "key", escalation = "sudo", jump = { addr = "bastion" } },
+    { addr = "relay", config_path = "/etc/postfix/virtual" },
+    { addr = "mx", port = 22, config_path = "/etc/aliases", format = "aliases", auth = "agent", escalation = "doas" },
+    { addr = "mx", port = 2222, config_path = "/etc/postfix/transport", format = "transport" },
+    { addr = "mx", username = "admin", config_path = "/etc/postfix/access", format = "access" },
+    { addr = "relay", config_path = "/etc/postfix/transport", format = "transport" },
+]
+"#,
+        );
+
+        let hosts = hosts(&servers);
+        // In the order the hosts first appear, with their maps in order
+        assert_eq!(
+            hosts
+                .iter()
+                .map(|(host, maps)| (host.to_string(), maps.clone()))
+                .collect::<Vec<_>>(),
+            [
+                ("mx:22".to_owned(), vec![0, 2]),
+                ("relay:22".to_owned(), vec![1, 5]),
+                ("mx:2222".to_owned(), vec![3]),
+                ("admin@mx:22".to_owned(), vec![4]),
+            ]
+        );
+
+        // The first map of the host decides how to log into it
+        let (mx, _) = &hosts[0];
+        assert_eq!(mx.auth, AuthMethod::Key);
+        assert_eq!(mx.escalation, Escalation::Sudo);
+        assert_eq!(
+            mx.jump.as_ref().map(|jump| jump.addr.as_str()),
+            Some("bastion")
+        );
     }
 }
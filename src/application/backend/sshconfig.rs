@@ -0,0 +1,313 @@
+use std::path::PathBuf;
+
+// Settings of a host read from the OpenSSH client configuration, None where it doesn't set them
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HostConfig {
+    pub host_name: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    // Every IdentityFile, ssh tries them in order
+    pub identity_files: Vec<String>,
+    pub proxy_jump: Option<String>,
+}
+
+// A Host section: its patterns and the options below it, with the keywords in lowercase
+#[derive(Clone, Debug)]
+struct Section {
+    patterns: Vec<String>,
+    options: Vec<(String, String)>,
+}
+
+// The sections of an ssh_config file, only the Host ones are understood
+#[derive(Clone, Debug, Default)]
+pub struct SshConfig {
+    sections: Vec<Section>,
+}
+
+impl SshConfig {
+    // Read ~/.ssh/config, a missing or unreadable file is an empty configuration
+    pub fn load() -> Self {
+        let Some(path) = std::env::var_os("HOME")
+            .or_else(|| std::env::var_os("USERPROFILE"))
+            .map(|home| PathBuf::from(home).join(".ssh").join("config"))
+        else {
+            return Self::default();
+        };
+
+        match std::fs::read_to_string(&path) {
+            Ok(text) => Self::parse(&text),
+            Err(error) => {
+                log::trace!("Can't read {}: {error}", path.display());
+                Self::default()
+            }
+        }
+    }
+
+    pub fn parse(text: &str) -> Self {
+        // Options before the first Host apply to every host
+        let mut sections = vec![Section {
+            patterns: vec!["*".to_owned()],
+            options: vec![],
+        }];
+
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            // The keyword is separated from its arguments by spaces or an equal sign
+            let (keyword, arguments) = line
+                .split_once(|c: char| c.is_whitespace() || c == '=')
+                .unwrap_or((line, ""));
+            let keyword = keyword.to_lowercase();
+            let arguments = arguments.trim_start_matches(|c: char| c.is_whitespace() || c == '=');
+
+            match keyword.as_str() {
+                "host" => sections.push(Section {
+                    patterns: arguments
+                        .split_whitespace()
+                        .map(|pattern| pattern.trim_matches('"').to_owned())
+                        .collect(),
+                    options: vec![],
+                }),
+                // Match conditions aren't evaluated, their options never apply
+                "match" => sections.push(Section {
+                    patterns: vec![],
+                    options: vec![],
+                }),
+                "include" => log::warn!("Include isn't supported in the ssh config: {line}"),
+                _ => {
+                    if let Some(section) = sections.last_mut() {
+                        section
+                            .options
+                            .push((keyword, arguments.trim().trim_matches('"').to_owned()));
+                    }
+                }
+            }
+        }
+
+        Self { sections }
+    }
+
+    // Settings for a host, the first value found for every option wins as in OpenSSH, except IdentityFile which adds up
+    pub fn host(&self, alias: &str) -> HostConfig {
+        let mut host = HostConfig::default();
+
+        for section in self
+            .sections
+            .iter()
+            .filter(|section| matches(&section.patterns, alias))
+        {
+            for (keyword, value) in section.options.iter() {
+                match keyword.as_str() {
+                    "hostname" if host.host_name.is_none() => {
+                        host.host_name = Some(value.replace("%h", alias))
+                    }
+                    "port" if host.port.is_none() => host.port = value.parse().ok(),
+                    "user" if host.user.is_none() => host.user = Some(value.clone()),
+                    "identityfile" if !host.identity_files.contains(value) => {
+                        host.identity_files.push(value.clone())
+                    }
+                    "proxyjump" if host.proxy_jump.is_none() => {
+                        host.proxy_jump = Some(value.clone())
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        host
+    }
+
+    // Names of the Host entries matching pattern, in the order they appear. Entries with wildcards or negations
+    // describe many hosts and aren't hosts themselves
+    pub fn aliases(&self, pattern: &str) -> Vec<String> {
+        let mut aliases: Vec<String> = vec![];
+
+        for name in self.sections.iter().skip(1).flat_map(|section| {
+            section
+                .patterns
+                .iter()
+                .filter(|name| !name.contains(['*', '?', '!']))
+        }) {
+            if glob(pattern, name) && !aliases.contains(name) {
+                aliases.push(name.clone());
+            }
+        }
+
+        aliases
+    }
+}
+
+// Whether a host matches the patterns of a Host line: any of them matches and none of the negated ones does
+fn matches(patterns: &[String], host: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns {
+        match pattern.strip_prefix('!') {
+            Some(negated) if glob(negated, host) => return false,
+            Some(_) => {}
+            None => matched |= glob(pattern, host),
+        }
+    }
+    matched
+}
+
+// Match text against a pattern where * is any sequence of characters and ? any single character
+fn glob(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    // Position of the last * and of the text it was tried at, to backtrack
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// A ProxyJump destination: [user@]host[:port], optionally as an ssh:// URI
+pub struct JumpSpec {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl JumpSpec {
+    // The hosts of a ProxyJump value in connection order, empty for none
+    pub fn parse_list(value: &str) -> Vec<Self> {
+        if value.eq_ignore_ascii_case("none") {
+            return vec![];
+        }
+
+        value
+            .split(',')
+            .map(|spec| spec.trim())
+            .filter(|spec| !spec.is_empty())
+            .map(|spec| {
+                let spec = spec.strip_prefix("ssh://").unwrap_or(spec);
+                let (user, address) = match spec.rsplit_once('@') {
+                    Some((user, address)) => (Some(user.to_owned()), address),
+                    None => (None, spec),
+                };
+                // IPv6 addresses are written between brackets when they have a port
+                let (host, port) = match address.strip_prefix('[') {
+                    Some(rest) => match rest.split_once(']') {
+                        Some((host, port)) => (host, port.strip_prefix(':')),
+                        None => (rest, None),
+                    },
+                    None => match address.split_once(':') {
+                        Some((host, port)) if !port.contains(':') => (host, Some(port)),
+                        _ => (address, None),
+                    },
+                };
+
+                Self {
+                    user,
+                    host: host.to_owned(),
+                    port: port.and_then(|port| port.parse().ok()),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "\
+# global options come first
+User global
+
+Host mail-relay relay-*
+    HostName %h.example.com
+    Port 2222
+    IdentityFile \"~/.ssh/id relay\"
+
+Host relay-2 !relay-3
+    HostName=other.example.com
+    ProxyJump admin@bastion:2200,ssh://[2001:db8::1]:22
+
+Match user root
+    Port 1
+
+Host *
+    Port 22
+    User fallback
+    IdentityFile ~/.ssh/id_fallback
+";
+
+    #[test]
+    fn first_value_wins_across_sections() {
+        let config = SshConfig::parse(CONFIG);
+
+        assert_eq!(
+            config.host("relay-2"),
+            HostConfig {
+                host_name: Some("relay-2.example.com".to_owned()),
+                port: Some(2222),
+                user: Some("global".to_owned()),
+                identity_files: vec![
+                    "~/.ssh/id relay".to_owned(),
+                    "~/.ssh/id_fallback".to_owned()
+                ],
+                proxy_jump: Some("admin@bastion:2200,ssh://[2001:db8::1]:22".to_owned()),
+            }
+        );
+        // Negated patterns exclude the host from the whole section
+        assert_eq!(config.host("relay-3").proxy_jump, None);
+        // Match sections are never applied
+        assert_eq!(config.host("unknown").port, Some(22));
+        assert_eq!(config.host("unknown").host_name, None);
+    }
+
+    #[test]
+    fn aliases_skip_wildcard_entries() {
+        let config = SshConfig::parse(CONFIG);
+
+        assert_eq!(config.aliases("*"), ["mail-relay", "relay-2"]);
+        assert_eq!(config.aliases("relay-?"), ["relay-2"]);
+        assert!(config.aliases("none").is_empty());
+    }
+
+    #[test]
+    fn proxy_jump_lists_are_parsed() {
+        let hops = JumpSpec::parse_list("admin@bastion:2200, ssh://[2001:db8::1]:22,plain");
+        let hops: Vec<(Option<&str>, &str, Option<u16>)> = hops
+            .iter()
+            .map(|hop| (hop.user.as_deref(), hop.host.as_str(), hop.port))
+            .collect();
+
+        assert_eq!(
+            hops,
+            [
+                (Some("admin"), "bastion", Some(2200)),
+                (None, "2001:db8::1", Some(22)),
+                (None, "plain", None),
+            ]
+        );
+        assert!(JumpSpec::parse_list("none").is_empty());
+    }
+
+    #[test]
+    fn glob_backtracks() {
+        assert!(glob("*.example.com", "a.b.example.com"));
+        assert!(glob("m?il*", "mail-relay"));
+        assert!(!glob("*.example.com", "example.com"));
+    }
+}
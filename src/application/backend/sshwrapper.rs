@@ -1,267 +1,1134 @@
 use std::{
-    borrow::BorrowMut,
-    collections::HashMap,
+    cell::Cell,
     error::Error,
     ffi::OsStr,
-    io::{ErrorKind, Write},
-    net::TcpStream,
-    path::Path,
-    time::Duration,
+    io::{ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
-use ssh::LocalSession;
-use tempfile::NamedTempFile;
+use ssh2::{Channel, ErrorCode, MethodType, Session};
 
-use super::server::Server;
+use super::{
+    backups::{expired, Backup},
+    hostkeys,
+    lock::{LockOwner, LockStatus},
+    mapdocument::MapDocument,
+    merge::{content_hash, Snapshot},
+    remotecommand::{CommandOutput, StepResult, UploadOutcome, UploadStep},
+    server::{AuthMethod, AuthStatus, Escalation, Hop, HopStatus, Host, Server},
+    tunnel,
+};
+
+// Timeout for connecting to a host, in milliseconds, so unreachable hosts fail the login quickly
+const CONNECT_TIMEOUT: u32 = 5_000;
+
+// Timeout for every blocking operation of the session, in milliseconds. libssh2 applies it to each call, and
+// post_install commands (postmap on a big map, postfix reload) may print nothing for longer than the connect timeout
+const TIMEOUT: u32 = 30_000;
+
+// Seconds between keepalives, so firewalls and the servers don't drop idle sessions
+pub const KEEPALIVE_INTERVAL: u32 = 30;
+
+// Key exchange methods offered to the servers, without the weak diffie-hellman-group1-sha1
+const KEX_METHODS: &str =
+    "curve25519-sha256,curve25519-sha256@libssh.org,ecdh-sha2-nistp256,ecdh-sha2-nistp384,\
+ecdh-sha2-nistp521,diffie-hellman-group-exchange-sha256,diffie-hellman-group16-sha512,\
+diffie-hellman-group18-sha512,diffie-hellman-group14-sha256,diffie-hellman-group14-sha1";
+
+// libssh2 error codes for rejected credentials (as opposed to network or protocol errors)
+const AUTHENTICATION_FAILED: i32 = -18;
+const PUBLICKEY_UNVERIFIED: i32 = -19;
+
+// Prompt sudo shows instead of the localized one, so it can be recognized
+const SUDO_PROMPT: &str = "[email-forward-manager] password:";
+
+// Messages printed by su, sudo and doas when the password is wrong, in lowercase
+const REJECTED_MESSAGES: [&str; 4] = [
+    "authentication failure",
+    "authentication failed",
+    "sorry, try again",
+    "incorrect password",
+];
+
+// Messages printed by doas -n when the rules ask for a password, in lowercase
+const DOAS_PASSWORD_REQUIRED: [&str; 2] = ["authorization required", "authentication required"];
+
+// Quote a text so the remote shell takes it as a single word
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+// Whether the output is a password prompt and nothing else. Only the start of the output can be a prompt, a line of the
+// command ending in a colon (a warning of postfix check) isn't one
+fn is_password_prompt(output: &str, escalation: Escalation) -> bool {
+    let output = output.trim();
+
+    match escalation {
+        Escalation::Sudo => output == SUDO_PROMPT,
+        // Password:, Contraseña: (su may be localized) or doas (user@host) password:
+        Escalation::Su | Escalation::Doas => {
+            output == "Password:"
+                || output == "Contraseña:"
+                || output
+                    .strip_prefix("doas (")
+                    .and_then(|rest| rest.strip_suffix(") password:"))
+                    .is_some_and(|user| !user.contains('\n'))
+        }
+        Escalation::Root => false,
+    }
+}
+
+// Host key algorithms offered to a host: the ones of its known keys first, then the rest in libssh2's order. RSA keys
+// are known as ssh-rsa, but they are used with the SHA-2 signatures too
+fn host_key_methods(session: &Session, addr: &str, port: u16) -> Result<String, ssh2::Error> {
+    let supported = session.supported_algs(MethodType::HostKey)?;
+
+    let mut methods: Vec<&str> = vec![];
+    for algorithm in hostkeys::known_algorithms(session, addr, port) {
+        let variants = match algorithm.as_str() {
+            "ssh-rsa" => vec!["rsa-sha2-512", "rsa-sha2-256", "ssh-rsa"],
+            _ => vec![algorithm.as_str()],
+        };
+        methods.extend(
+            supported
+                .iter()
+                .copied()
+                .filter(|method| variants.contains(method)),
+        );
+    }
+    log::trace!("Known host key algorithms for {addr}:{port}: {methods:?}");
+
+    for method in supported {
+        if !methods.contains(&method) {
+            methods.push(method);
+        }
+    }
 
-// Wrapper around the ssh connection with the servers
+    Ok(methods.join(","))
+}
+
+// Read the output of a command on a non-blocking session until it finishes, answering the password prompt as soon as
+// it shows up instead of guessing when. Returns what was read from the prompt stream and from the other one
+fn read_output(
+    session: &Session,
+    channel: &mut Channel,
+    command: &str,
+    escalation: Escalation,
+    password: &str,
+) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+    // sudo -S shows the prompt in stderr, su and doas in the terminal
+    let (mut prompt_stream, mut other_stream) = if escalation == Escalation::Sudo {
+        (channel.stderr(), channel.stream(0))
+    } else {
+        (channel.stream(0), channel.stderr())
+    };
+
+    let mut data = vec![];
+    let mut other = vec![];
+    // Where the output after the answered prompt starts
+    let mut answered_at = None;
+    // The prompt must come before anything else, once there is other output the command is running
+    let mut expecting_prompt = true;
+    let mut last_read = Instant::now();
+
+    loop {
+        let prompted = read_available(&mut prompt_stream, &mut data)?;
+        let read = read_available(&mut other_stream, &mut other)? || prompted;
+
+        if !read {
+            // Nothing is left to read once the command closed its output
+            if channel.eof() {
+                break;
+            }
+            if last_read.elapsed() > Duration::from_millis(TIMEOUT as u64) {
+                return Err(Box::new(std::io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!(
+                        "No answer from '{command}' in {} seconds, output: {}",
+                        TIMEOUT / 1000,
+                        String::from_utf8_lossy(&[data.as_slice(), other.as_slice()].concat())
+                            .trim()
+                    ),
+                )));
+            }
+            std::thread::sleep(Duration::from_millis(10));
+            continue;
+        }
+        last_read = Instant::now();
+        if !prompted {
+            continue;
+        }
+
+        let output = String::from_utf8_lossy(&data[answered_at.unwrap_or(0)..]);
+        match answered_at {
+            None if !expecting_prompt => {}
+            // A line that isn't a prompt means there won't be one
+            None if !is_password_prompt(&output, escalation) => {
+                expecting_prompt = !output.trim_start().contains('\n');
+            }
+            None if password.is_empty() => {
+                return Err(Box::new(std::io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!(
+                        "{escalation} asked for a password, but none was typed in the login screen"
+                    ),
+                )));
+            }
+            None => {
+                // The password is short, write it in one go
+                session.set_blocking(true);
+                let written = channel.write_all(format!("{password}\n").as_bytes());
+                session.set_blocking(false);
+                written?;
+                answered_at = Some(data.len());
+            }
+            // su and doas don't ask twice, sudo asks again when the password is wrong
+            Some(_)
+                if escalation == Escalation::Sudo && output.trim_end().ends_with(SUDO_PROMPT) =>
+            {
+                return Err(Box::new(std::io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "sudo rejected the password",
+                )));
+            }
+            Some(_) => {}
+        }
+    }
+
+    Ok((data, other))
+}
+
+// Append what a stream of a non-blocking channel has now, returning whether there was anything
+fn read_available(stream: &mut impl Read, output: &mut Vec<u8>) -> std::io::Result<bool> {
+    let mut buffer = [0; 4096];
+    let mut read_any = false;
+
+    loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => return Ok(read_any),
+            Ok(read) => {
+                output.extend_from_slice(&buffer[..read]);
+                read_any = true;
+            }
+            Err(error) if error.kind() == ErrorKind::WouldBlock => return Ok(read_any),
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+// Private key used for key authentication: the configured one, or the first of the usual ones that exists
+pub fn identity_file(configured: Option<&str>) -> Option<PathBuf> {
+    let home = std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from);
+
+    match configured {
+        Some(path) => match (path.strip_prefix("~/"), home) {
+            (Some(path), Some(home)) => Some(home.join(path)),
+            _ => Some(PathBuf::from(path)),
+        },
+        None => {
+            let directory = home?.join(".ssh");
+            ["id_ed25519", "id_ecdsa", "id_rsa"]
+                .iter()
+                .map(|name| directory.join(name))
+                .find(|path| path.exists())
+        }
+    }
+}
+
+// Wrapper around the ssh connection with a host
 pub struct SSHWrapper {
-    // The server's information
-    server: Server,
+    // The host logged into, its maps are passed to every operation
+    host: Host,
     // Username to use in authentication
     username: String,
     // Password
     password: String,
-    // root password to allow uploading the configuration
+    // Passphrase of the private key, empty for keys without passphrase
+    passphrase: String,
+    // root password for su escalation, sudo and doas use the login password
     root_password: String,
     // The actual ssh/scp client instance
-    client: Option<LocalSession<TcpStream>>,
+    client: Option<Session>,
+    // doas -n failed because the rules of the host ask for a password, so it's typed from then on
+    doas_password: Cell<bool>,
 }
 
 impl SSHWrapper {
-    // Try to authenticate with the server
-    pub fn authenticate(&mut self) -> Result<bool, Box<dyn Error>> {
-        log::trace!(
-            "Authenticating {}:{}@{}:{}",
-            self.username,
-            self.password,
-            self.server.addr,
-            self.server.port
-        );
+    // Try to authenticate with the server, going through its jump hosts first. progress is told the status of every hop
+    // as it changes
+    pub fn authenticate(
+        &mut self,
+        progress: impl Fn(&[HopStatus]),
+    ) -> Result<bool, Box<dyn Error>> {
+        // A session that died is replaced, not reused
+        self.client = None;
 
-        // Try to create a session
-        match ssh::create_session()
-            // Using 2 seconds timeout seems to be enough
-            .timeout(Some(Duration::from_secs(5)))
-            // Set the username
-            .username(&self.username)
-            // password
-            .password(&self.password)
-            // Add this old (and apparently deprecated) pubkey algorithm
-            .add_pubkey_algorithms(ssh::algorithm::PubKey::SshEd25519)
-            // Connect to the server
-            .connect(format!("{}:{}", self.server.addr, self.server.port))
-        {
-            // If the client connects successfully
-            Ok(client) => {
-                self.client = Some(client.run_local());
-                return Ok(true);
+        let hops = self.host.hops(&self.username);
+        let mut status: Vec<HopStatus> = hops
+            .iter()
+            .map(|hop| HopStatus {
+                name: hop.to_string(),
+                status: AuthStatus::Unknown,
+            })
+            .collect();
+
+        // Connection through the previous hop, None for the first one
+        let mut stream = None;
+        for (index, hop) in hops.iter().enumerate() {
+            status[index].status = AuthStatus::InProgress;
+            progress(&status);
+
+            let result = self.login(hop, stream.take());
+            status[index].status = match &result {
+                Ok(Some(_)) => AuthStatus::Authenticated,
+                _ => AuthStatus::Failed,
+            };
+            progress(&status);
+
+            let session = match result? {
+                Some(session) => session,
+                // Rejected credentials on the server itself are signaled, on a jump host they are an error
+                None if index == hops.len() - 1 => return Ok(false),
+                None => return Err(format!("Authentication failed on jump host {hop}").into()),
+            };
+
+            match hops.get(index + 1) {
+                Some(next) => stream = Some(tunnel::forward(session, &next.addr, next.port)?),
+                None => self.client = Some(session),
             }
-            Err(error) => match error {
-                // If there was an authentication error just signal it
-                ssh::SshError::AuthError => return Ok(false),
-                // Other errors
-                other => {
-                    log::trace!("Unhandled error: {other:#?}");
-                    return Err(Box::new(other));
+        }
+
+        Ok(true)
+    }
+
+    // Log into a hop over stream, or over a new connection if there is none. None if the credentials were rejected
+    fn login(
+        &self,
+        hop: &Hop,
+        stream: Option<TcpStream>,
+    ) -> Result<Option<Session>, Box<dyn Error>> {
+        log::trace!("Authenticating {hop} using {:?}", hop.auth);
+
+        let stream = match stream {
+            Some(stream) => stream,
+            None => {
+                // Connect to the first address the name resolves to
+                let address = (hop.addr.as_str(), hop.port)
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| {
+                        std::io::Error::new(
+                            ErrorKind::NotFound,
+                            format!("Can't resolve {}", hop.addr),
+                        )
+                    })?;
+                TcpStream::connect_timeout(&address, Duration::from_millis(CONNECT_TIMEOUT as u64))?
+            }
+        };
+
+        let mut session = Session::new()?;
+        session.set_timeout(TIMEOUT);
+        session.set_keepalive(true, KEEPALIVE_INTERVAL);
+        session.set_tcp_stream(stream);
+        session.method_pref(MethodType::Kex, KEX_METHODS)?;
+        session.method_pref(
+            MethodType::HostKey,
+            &host_key_methods(&session, &hop.addr, hop.port)?,
+        )?;
+        session.handshake()?;
+
+        // Don't send any credential to a server that can't be trusted
+        hostkeys::verify(&session, &hop.addr, hop.port)?;
+
+        let result = match hop.auth {
+            AuthMethod::Password => session.userauth_password(&hop.username, &self.password),
+            AuthMethod::Key => {
+                let identity = identity_file(hop.identity_file.as_deref()).ok_or_else(|| {
+                    std::io::Error::new(
+                        ErrorKind::NotFound,
+                        format!("No private key found for {hop}, set its identity_file"),
+                    )
+                })?;
+                log::trace!("Using private key {}", identity.display());
+
+                // The passphrase is only sent for protected keys
+                let passphrase = Some(self.passphrase.as_str()).filter(|x| !x.is_empty());
+                session.userauth_pubkey_file(&hop.username, None, &identity, passphrase)
+            }
+            AuthMethod::Agent => {
+                // Try every key in the agent until the server accepts one
+                let mut agent = session.agent()?;
+                agent.connect()?;
+                agent.list_identities()?;
+
+                let mut result = Err(ssh2::Error::new(
+                    ErrorCode::Session(AUTHENTICATION_FAILED),
+                    "The ssh-agent has no keys",
+                ));
+                for identity in agent.identities()? {
+                    result = agent.userauth(&hop.username, &identity);
+                    if result.is_ok() {
+                        break;
+                    }
                 }
-            },
+                let _ = agent.disconnect();
+                result
+            }
+        };
+
+        match result {
+            Ok(_) if session.authenticated() => Ok(Some(session)),
+            Ok(_) => Ok(None),
+            // If there was an authentication error just signal it
+            Err(error)
+                if matches!(
+                    error.code(),
+                    ErrorCode::Session(AUTHENTICATION_FAILED | PUBLICKEY_UNVERIFIED)
+                ) =>
+            {
+                log::trace!("Authentication rejected: {error}");
+                Ok(None)
+            }
+            // Other errors (unreadable key, wrong passphrase, agent not running...)
+            Err(error) => {
+                log::trace!("Unhandled error: {error:#?}");
+                Err(Box::new(error))
+            }
         }
     }
 
     // Create a new wrapper
-    pub fn new(server: Server, username: String, password: String, root_password: String) -> Self {
+    pub fn new(
+        host: Host,
+        username: String,
+        password: String,
+        passphrase: String,
+        root_password: String,
+    ) -> Self {
         Self {
-            server,
+            host,
             username,
             password,
+            passphrase,
             root_password,
             client: None,
+            doas_password: Cell::new(false),
         }
     }
 
-    // Fetch the virtual users list from the server, parses it and returns it
-    pub fn get_virtual_users(&mut self) -> Result<HashMap<String, Vec<String>>, Box<dyn Error>> {
-        // Create a temporary directory in the host so we can safely fetch the file from the server
-        let temp_directory = tempfile::tempdir()?;
-
-        // full path for the temporary directory plus the filename
-        let local_path = temp_directory
-            .path()
-            .join(format!("temp_{}", self.server.addr.clone()));
-
-        // String copy of the temporary file name
-        let local_path_string = local_path.to_str().unwrap().to_owned();
-
-        // Avoid querying if there is no client to query to
-        if let Some(client) = self.client.borrow_mut() {
-            // Create a scp client instance to download the file
-            let scp = client.open_scp()?;
-
-            // Download the file into the local temporary directory
-            if let Err(error) =
-                scp.download(&local_path.as_path(), &Path::new(&self.server.config_path))
+    pub fn host(&self) -> &Host {
+        &self.host
+    }
+
+    // Send a keepalive if one is due, a failure means the session is dead
+    pub fn keepalive(&mut self) {
+        if let Some(session) = &self.client {
+            if let Err(error) = session.keepalive_send() {
+                log::warn!("Session with {} lost: {error}", self.host);
+                self.client = None;
+            }
+        }
+    }
+
+    // Whether the session still works, only a command's round trip proves it
+    pub fn is_alive(&self) -> bool {
+        self.client.is_some() && self.exec("true").is_ok_and(|output| output.success())
+    }
+
+    // Get the session, if the wrapper is authenticated
+    fn session(&self) -> Result<&Session, std::io::Error> {
+        self.client.as_ref().ok_or(std::io::Error::other(
+            "There is no SSH Client instance in this wrapper",
+        ))
+    }
+
+    // Download a map, one that doesn't exist yet is empty so it can be created
+    fn download_map(&self, path: &str) -> Result<String, Box<dyn Error>> {
+        if !self
+            .exec(&format!("test -e {}", shell_quote(path)))?
+            .success()
+        {
+            log::warn!(
+                "{path} doesn't exist on {}, starting an empty map",
+                self.host
+            );
+            return Ok(String::new());
+        }
+
+        self.download(path)
+    }
+
+    // Download a remote file through scp
+    fn download(&self, path: &str) -> Result<String, Box<dyn Error>> {
+        let (mut channel, _) = self.session()?.scp_recv(Path::new(path))?;
+
+        let mut data = String::new();
+        channel.read_to_string(&mut data)?;
+
+        channel.send_eof()?;
+        channel.wait_eof()?;
+        channel.close()?;
+        channel.wait_close()?;
+
+        Ok(data)
+    }
+
+    // Run a command as the login user
+    fn exec(&self, command: &str) -> Result<CommandOutput, Box<dyn Error>> {
+        self.run(command, Escalation::Root)
+    }
+
+    // Run a command as root using the server's escalation method
+    fn exec_as_root(&self, command: &str) -> Result<CommandOutput, Box<dyn Error>> {
+        self.run(command, self.host.escalation)
+    }
+
+    // Run a command on its own exec channel, answering the escalation's password prompt if there is one.
+    // A command that runs but fails isn't an error, its exit status tells it
+    fn run(&self, command: &str, escalation: Escalation) -> Result<CommandOutput, Box<dyn Error>> {
+        // doas is tried without a password first, so hosts with nopass rules never show a prompt
+        if escalation == Escalation::Doas && !self.doas_password.get() {
+            let wrapped = format!("doas -n sh -c {}", shell_quote(command));
+            let output = self.execute(command, &wrapped, Escalation::Root, "")?;
+
+            let stderr = output.stderr.to_lowercase();
+            if output.success()
+                || !DOAS_PASSWORD_REQUIRED
+                    .iter()
+                    .any(|message| stderr.contains(message))
             {
+                return Ok(output);
+            }
+            log::trace!("doas needs a password on {}", self.host);
+            self.doas_password.set(true);
+        }
+
+        let (wrapped, password) = match escalation {
+            // Force the C locale so su and PAM print their messages in english
+            Escalation::Su => (
+                format!("LC_ALL=C su root -c {}", shell_quote(command)),
+                &self.root_password,
+            ),
+            Escalation::Sudo => (
+                format!(
+                    "sudo -S -p {} sh -c {}",
+                    shell_quote(SUDO_PROMPT),
+                    shell_quote(command)
+                ),
+                &self.password,
+            ),
+            Escalation::Doas => (
+                format!("doas sh -c {}", shell_quote(command)),
+                &self.password,
+            ),
+            Escalation::Root => (command.to_owned(), &self.password),
+        };
+
+        self.execute(command, &wrapped, escalation, password)
+    }
+
+    // Run the wrapped form of a command, answering the password prompt of the escalation
+    fn execute(
+        &self,
+        command: &str,
+        wrapped: &str,
+        escalation: Escalation,
+        password: &str,
+    ) -> Result<CommandOutput, Box<dyn Error>> {
+        log::trace!("Running {wrapped}");
+
+        let mut channel = self.session()?.channel_session()?;
+        // su and doas only read the password from a terminal, which mixes stdout and stderr
+        if matches!(escalation, Escalation::Su | Escalation::Doas) {
+            channel.request_pty("xterm", None, None)?;
+        }
+        channel.exec(wrapped)?;
+
+        // The session doesn't block while the output is read, so neither stream can stall the command by filling its
+        // window while the other one is waited for
+        let session = self.session()?;
+        session.set_blocking(false);
+        let result = read_output(session, &mut channel, command, escalation, password);
+        session.set_blocking(true);
+
+        let (data, other) = match result {
+            Ok(output) => output,
+            Err(error) => {
+                let _ = channel.close();
+                return Err(error);
+            }
+        };
+        channel.wait_close()?;
+
+        let prompted = String::from_utf8_lossy(&data).replace(SUDO_PROMPT, "");
+        let other = String::from_utf8_lossy(&other).into_owned();
+        let (stdout, stderr) = if escalation == Escalation::Sudo {
+            (other, prompted)
+        } else {
+            (prompted, other)
+        };
+        let output = CommandOutput {
+            command: command.to_owned(),
+            stdout,
+            stderr,
+            exit_status: channel.exit_status()?,
+        };
+        log::debug!("Result of '{command}': {output:?}");
+
+        // Tell a wrong password apart from a failing command
+        if escalation != Escalation::Root && !output.success() {
+            let lowercase = format!("{}\n{}", output.stdout, output.stderr).to_lowercase();
+            if REJECTED_MESSAGES
+                .iter()
+                .any(|message| lowercase.contains(message))
+            {
+                return Err(Box::new(std::io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("{escalation} rejected the password: {}", output.message()),
+                )));
+            }
+        }
+
+        Ok(output)
+    }
+
+    // Fetch the virtual users list from the server, parses it and returns it
+    pub fn get_virtual_users(&self, server: &Server) -> Result<Snapshot, Box<dyn Error>> {
+        // Get the modification time before downloading, so a change made in between is noticed later
+        let mtime = self.remote_mtime(server);
+
+        // Download the file into memory
+        let data = match self.download_map(&server.config_path) {
+            Ok(data) => {
+                // Show success message in the logs
+                log::trace!("Got {}:{}", server, server.config_path);
+                data
+            }
+            Err(error) => {
                 // Show the error message in the logs
                 log::error!(
-                    "Can't download {}:{}, trying virtualuser: {error:?}",
-                    self.server,
-                    self.server.config_path
+                    "Can't download {}:{}: {error:?}",
+                    server,
+                    server.config_path
                 );
 
                 // Return the error to the caller
-                return Err(Box::new(error));
-            } else {
-                // Show success message in the logs
-                log::trace!("Got {}:{}", self.server, self.server.config_path);
+                return Err(error);
             }
+        };
+
+        // Parse the file into the document model
+        let document = self.parse(server, &data)?;
+
+        Ok(Snapshot::new(&data, mtime, document))
+    }
+
+    // Parse a downloaded map
+    fn parse(&self, server: &Server, data: &str) -> Result<MapDocument, Box<dyn Error>> {
+        let document = server.format.format().parse(data).map_err(|error| {
+            log::error!("Server {} path {}: {error}", server, server.config_path);
+
+            std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Error parsing file: {error}"),
+            )
+        })?;
+
+        Ok(document)
+    }
+
+    // Modification time of the map, in seconds since the epoch. GNU stat takes -c, the BSD one -f. Where neither works
+    // the change is still noticed by the content hash
+    fn remote_mtime(&self, server: &Server) -> Option<i64> {
+        let path = shell_quote(&server.config_path);
+        let output = self
+            .exec(&format!(
+                "stat -c %Y {path} 2>/dev/null || stat -f %m {path}"
+            ))
+            .map_err(|error| log::warn!("Can't get the modification time: {error}"))
+            .ok()?;
+
+        output.stdout.trim().parse().ok()
+    }
+
+    // Advisory lock file of the map, next to it
+    fn lock_path(&self, server: &Server) -> String {
+        format!("{}.lock", server.config_path)
+    }
+
+    // Take the lock of the map, unless someone else holds it. noclobber makes creating the file fail if it exists
+    pub fn lock(&self, server: &Server) -> Result<LockStatus, Box<dyn Error>> {
+        let owner = LockOwner::current();
+        let lock_path = shell_quote(&self.lock_path(server));
+
+        let output = self.exec_as_root(&format!(
+            "(set -C; printf %s {} > {lock_path}) 2>/dev/null; cat {lock_path}",
+            shell_quote(&owner.render()),
+        ))?;
+        if !output.success() {
+            return Err(Box::new(std::io::Error::other(format!(
+                "Can't lock {}: {}",
+                server.config_path,
+                output.message()
+            ))));
+        }
+
+        // An unreadable lock is shown as someone else's, so it can be broken
+        Ok(match LockOwner::parse(&output.stdout) {
+            Some(holder) if holder.is_current_process() => LockStatus::Ours(holder),
+            Some(holder) => LockStatus::Theirs(holder),
+            None => LockStatus::Theirs(LockOwner {
+                operator: "?".to_owned(),
+                host: "?".to_owned(),
+                pid: 0,
+                since: output.stdout.trim().to_owned(),
+            }),
+        })
+    }
+
+    // Release the lock, only if it still is the one this application took
+    pub fn unlock(&self, server: &Server, owner: &LockOwner) -> Result<(), Box<dyn Error>> {
+        let lock_path = shell_quote(&self.lock_path(server));
+
+        let output = self.exec_as_root(&format!(
+            "if [ \"$(cat {lock_path} 2>/dev/null)\" = {} ]; then rm -f {lock_path}; fi",
+            shell_quote(&owner.render()),
+        ))?;
+        if !output.success() {
+            return Err(Box::new(std::io::Error::other(format!(
+                "Can't unlock {}: {}",
+                server.config_path,
+                output.message()
+            ))));
+        }
+
+        Ok(())
+    }
+
+    // Remove the lock whoever holds it, and take it
+    pub fn break_lock(&self, server: &Server) -> Result<LockStatus, Box<dyn Error>> {
+        let output =
+            self.exec_as_root(&format!("rm -f {}", shell_quote(&self.lock_path(server))))?;
+        if !output.success() {
+            return Err(Box::new(std::io::Error::other(format!(
+                "Can't break the lock of {}: {}",
+                server.config_path,
+                output.message()
+            ))));
+        }
+
+        self.lock(server)
+    }
+
+    // Start of the name of the map's backups: the map's file name, without characters the shell would expand
+    fn backup_prefix(&self, server: &Server) -> String {
+        Path::new(&server.config_path)
+            .file_name()
+            .unwrap_or(OsStr::new("virtual"))
+            .to_string_lossy()
+            .replace(
+                |c: char| !(c.is_ascii_alphanumeric() || "._-".contains(c)),
+                "_",
+            )
+    }
+
+    // List the backups of the map in the user's home, newest first
+    pub fn list_backups(&self, server: &Server) -> Result<Vec<Backup>, Box<dyn Error>> {
+        let prefix = self.backup_prefix(server);
+        // ls fails when there are no backups, that's an empty list
+        let output = self.exec(&format!(
+            "ls -1d \"$HOME\"/{prefix}_*.bak 2>/dev/null; true"
+        ))?;
+
+        Ok(Backup::parse_listing(&output.stdout, &prefix))
+    }
+
+    // Download and parse a backup
+    pub fn download_backup(
+        &self,
+        server: &Server,
+        path: &str,
+    ) -> Result<MapDocument, Box<dyn Error>> {
+        let data = self.download(path)?;
+        self.parse(server, &data)
+    }
+
+    // Delete the backups the server's retention policy doesn't keep, returning the remaining ones
+    pub fn prune_backups(&self, server: &Server) -> Result<Vec<Backup>, Box<dyn Error>> {
+        let backups = self.list_backups(server)?;
+        let expired = expired(
+            &backups,
+            server.keep_backups,
+            server.keep_backups_days,
+            chrono::Local::now().naive_local(),
+        );
+        if expired.is_empty() {
+            return Ok(backups);
+        }
+
+        let paths: Vec<String> = expired
+            .iter()
+            .map(|backup| shell_quote(&backup.path))
+            .collect();
+        log::trace!("Removing old backups: {}", paths.join(" "));
+        let output = self.exec(&format!("rm -f {}", paths.join(" ")))?;
+        if !output.success() {
+            return Err(Box::new(std::io::Error::other(format!(
+                "Can't remove old backups: {}",
+                output.message()
+            ))));
         }
 
-        // Try to read the downloaded file
-        log::trace!("Trying to open {local_path_string} to get the data");
-        let data = std::fs::read_to_string(local_path.clone())?;
+        self.list_backups(server)
+    }
 
-        let mut redirections: HashMap<String, Vec<String>> = Default::default();
+    // Upload configurations to server, running the steps in order until one fails
+    pub fn upload_configuration(&self, server: &Server) -> UploadOutcome {
+        let mut steps = vec![];
+        let mut state = UploadState::default();
 
-        // Parse the file into the redirections hash
-        for line in data.lines().map(|x| x.to_owned()) {
-            // Skip comments
-            if line.trim().starts_with("#") {
-                continue;
+        let hooks = server.post_install_commands();
+        let hook_steps = (0..hooks.len()).map(UploadStep::Hook);
+
+        for step in [
+            UploadStep::Validate,
+            UploadStep::Compare,
+            UploadStep::Backup,
+            UploadStep::Transfer,
+            UploadStep::Preflight,
+            UploadStep::Install,
+            UploadStep::Verify,
+        ]
+        .into_iter()
+        .chain(hook_steps)
+        {
+            let result = self.run_upload_step(step, server, &mut state);
+
+            let success = result.success();
+            steps.push(result);
+            if !success {
+                break;
             }
+        }
 
-            // Split the line in two
-            if let Some((email, rest)) = line.split_once(" ") {
-                // Split the second part to obtain redirection targets
-                let mut email_redirections: Vec<String> = rest
-                    .split(|x: char| x.is_whitespace() || x == ',')
-                    .map(|x| x.trim().to_owned())
-                    .collect();
-                // Remove empty entries
-                email_redirections.retain(|redirection| !redirection.is_empty());
-                // Remove duplicated entries
-                email_redirections.dedup();
-
-                // Insert the list into the redirections hash
-                redirections.insert(email.to_owned(), email_redirections);
-            } else {
-                // Show an error
-                log::error!(
-                    "Server {} path {local_path_string} Skipping line '{line}'",
-                    self.server
-                );
+        // Once the live map was replaced any failure leaves postfix in an unknown state, go back to the backup
+        let failed = steps.iter().any(|step| !step.success());
+        let installed = steps
+            .iter()
+            .any(|step| step.step == UploadStep::Install && step.success());
+        if failed && installed {
+            steps.push(self.run_upload_step(UploadStep::Rollback, server, &mut state));
+        }
 
-                return Err(Box::new(std::io::Error::new(
-                    ErrorKind::InvalidInput,
-                    "Error parsing file",
-                )));
+        // The uploaded copy belongs to the user, so it can be removed even if the install failed
+        if !state.uploaded_path.is_empty() {
+            if let Err(error) = self.exec(&format!("rm -f {}", shell_quote(&state.uploaded_path))) {
+                log::warn!("Can't remove {}: {error}", state.uploaded_path);
             }
         }
 
-        Ok(redirections)
+        // The installed map only becomes the new base if nothing failed afterwards
+        let installed = if failed { None } else { state.installed };
+
+        UploadOutcome {
+            steps,
+            installed,
+            remote_changed: state.remote_changed,
+        }
     }
 
-    // Upload configurations to server
-    pub fn upload_configuration(&mut self, server: Server) -> Result<(), Box<dyn Error>> {
-        // Get the client
-        let client = self.client.as_mut().ok_or(std::io::Error::new(
-            ErrorKind::Other,
-            "There is no SSH Client instance in this wrapper",
-        ))?;
+    // Run one of the upload steps, turning errors into a failed step
+    fn run_upload_step(
+        &self,
+        step: UploadStep,
+        server: &Server,
+        state: &mut UploadState,
+    ) -> StepResult {
+        let result = self
+            .upload_step(step, server, state)
+            .unwrap_or_else(|error| StepResult::failed(step, error));
+        log::trace!("Upload to {}: {result}", server);
+
+        result
+    }
 
-        let configuration_full_path = self.server.config_path.clone();
+    // Run one of the upload steps
+    fn upload_step(
+        &self,
+        step: UploadStep,
+        server: &Server,
+        state: &mut UploadState,
+    ) -> Result<StepResult, Box<dyn Error>> {
+        let configuration_full_path = &server.config_path;
         // Get the configuration file's name
-        let configuration_filename = Path::new(&configuration_full_path)
+        let configuration_filename = Path::new(configuration_full_path)
             .file_name()
             .unwrap_or(OsStr::new("virtual"))
-            .to_str()
-            .unwrap()
-            .to_owned();
+            .to_string_lossy();
 
         // Generate the configuration's payload
         let payload = server.payload();
 
-        // Create a local file to be sent to server
-        let mut local_file = NamedTempFile::new()?;
-        // Write the configuration payload and flush
-        local_file.write_all(payload.as_bytes())?;
-        local_file.flush()?;
+        let result = match step {
+            // The session is checked by the backend before the upload starts
+            UploadStep::Connect => StepResult::done(step),
+            // Refuse to upload entries postfix wouldn't understand
+            UploadStep::Validate => {
+                let errors = server.format.format().validate(&server.users);
+                if errors.is_empty() {
+                    StepResult::done(step)
+                } else {
+                    StepResult::failed(
+                        step,
+                        format!(
+                            "Invalid entries: {}",
+                            errors
+                                .iter()
+                                .map(|error| error.to_string())
+                                .collect::<Vec<String>>()
+                                .join("; ")
+                        ),
+                    )
+                }
+            }
+            // Refuse to overwrite changes made by someone else since the map was downloaded. The mtime is enough when it
+            // didn't change, otherwise the content tells whether the file was just touched
+            UploadStep::Compare => {
+                let base = &server.snapshot;
+                let mtime = self.remote_mtime(server);
+                if mtime.is_some() && mtime == base.mtime {
+                    return Ok(StepResult::done(step));
+                }
+
+                let data = self.download_map(configuration_full_path)?;
+                if content_hash(&data) == base.hash {
+                    StepResult::done(step)
+                } else {
+                    state.remote_changed =
+                        Some(Snapshot::new(&data, mtime, self.parse(server, &data)?));
+                    StepResult::failed(
+                        step,
+                        "The map was changed on the server since it was downloaded",
+                    )
+                }
+            }
+            // Create a backup for the remote configuration, printing its full path so it can be restored as root.
+            // A map that doesn't exist yet has nothing to back up
+            UploadStep::Backup => {
+                let output = self.exec(&format!(
+                    "if [ -e {path} ]; then backup=\"$HOME/{}_$(date +%Y-%m-%d_%H-%M-%S).bak\" && cp {path} \"$backup\" && echo \"$backup\"; fi",
+                    self.backup_prefix(server),
+                    path = shell_quote(configuration_full_path),
+                ))?;
+                state.backup_path = output.stdout.trim().to_owned();
+                StepResult::from_output(step, output)
+            }
+            // Upload the file to a new temporary file, so two uploads never write the same one
+            UploadStep::Transfer => {
+                let output = self.exec(&format!(
+                    "mktemp {}",
+                    shell_quote(&format!("/tmp/{configuration_filename}.XXXXXX"))
+                ))?;
+                if !output.success() {
+                    return Ok(StepResult::from_output(step, output));
+                }
+                state.uploaded_path = output.stdout.trim().to_owned();
+
+                let mut channel = self.session()?.scp_send(
+                    Path::new(state.uploaded_path.as_str()),
+                    0o600,
+                    payload.len() as u64,
+                    None,
+                )?;
+                channel.write_all(payload.as_bytes())?;
+                channel.send_eof()?;
+                channel.wait_eof()?;
+                channel.close()?;
+                channel.wait_close()?;
 
-        // Create a backup for the remote configuration
-        let mut shell = client.open_shell()?;
+                StepResult::from_output(step, output)
+            }
+            // Build a throwaway copy of the new map and check the whole postfix configuration, before touching the live map
+            UploadStep::Preflight => {
+                let build = server
+                    .format
+                    .format()
+                    .preflight("\"$dir/map\"")
+                    .map(|command| format!("{command} && "))
+                    .unwrap_or_default();
 
-        let _ = shell.write(
-            format!(
-                "cp '{}' {}\n",
-                self.server.config_path,
-                format!("~/{configuration_filename}_`date \"+%Y-%m-%d_%H-%M-%S\"`.bak")
-            )
-            .as_bytes(),
-        )?;
-        std::thread::sleep(Duration::from_secs(2));
-        log::debug!("Result: {}", String::from_utf8(shell.read()?)?);
-
-        // Create a scp client instance
-        let scp = client.open_scp()?;
-        // Upload the file
-        scp.upload(
-            &local_file.path().to_str().unwrap().to_owned(),
-            &format!("/tmp/{configuration_filename}"),
-        )?;
+                StepResult::from_output(
+                    step,
+                    self.exec_as_root(&format!(
+                        "dir=$(mktemp -d) && trap 'rm -rf \"$dir\"' EXIT && cp {} \"$dir/map\" && {build}postfix check",
+                        shell_quote(&state.uploaded_path),
+                    ))?,
+                )
+            }
+            // Replace the map as root
+            UploadStep::Install => StepResult::from_output(
+                step,
+                self.exec_as_root(&install_command(
+                    &state.uploaded_path,
+                    configuration_full_path,
+                ))?,
+            ),
+            // Download the allegedly uploaded configuration
+            UploadStep::Verify => {
+                let data = self.download(configuration_full_path)?;
 
-        // Create a shell so we can escalate privileges into root
-        let mut shell = client.open_shell()?;
-        // Read and discard the bash banner
+                log::trace!("Comparing:\n==========\n{data}\n==========\n{payload}\n==========",);
 
-        // Send and execute the command:
-        shell.write(
-            format!("su root -c 'cp /tmp/{configuration_filename} {configuration_full_path}; /etc/postfix/post_update'\n")
-                .as_bytes(),
-        )?;
+                // The document is rendered deterministically, so the uploaded file must match the payload exactly
+                if data == payload {
+                    state.installed = Some(Snapshot::new(
+                        &data,
+                        self.remote_mtime(server),
+                        server.users.clone(),
+                    ));
+                    StepResult::done(step)
+                } else {
+                    StepResult::failed(step, "Configuration was not updated!")
+                }
+            }
+            // Rebuild the database, reload postfix... a failing hook fails the upload
+            UploadStep::Hook(index) => StepResult::from_output(
+                step,
+                self.exec_as_root(&server.post_install_commands()[index])?,
+            ),
+            // Put the backup back in place and make postfix use it again, a map that didn't exist is removed
+            UploadStep::Rollback => {
+                // A new map is just removed: the hooks would rebuild or read the file that is no longer there
+                let command = if state.backup_path.is_empty() {
+                    format!("rm -f {}", shell_quote(configuration_full_path))
+                } else {
+                    let mut command = install_command(&state.backup_path, configuration_full_path);
+                    for hook in server.post_install_commands() {
+                        command = format!("{command} && {hook}");
+                    }
+                    command
+                };
 
-        // Sleep for a while until the prompt is shown
-        std::thread::sleep(Duration::from_millis(500));
-        shell.write((self.root_password.clone() + "\n").as_bytes())?;
-        // Sleep for a while until the file is properly modified
-        std::thread::sleep(Duration::from_millis(500));
-
-        // Create a scp client to download the allegedly uploaded configuration
-        let scp = client.open_scp()?;
-        // Create a temporary directory to get the configuration
-        let local_tmp_dir = tempfile::tempdir()?;
-
-        // Download the configuration
-        scp.download(local_tmp_dir.path(), Path::new(&configuration_full_path))?;
-        // Read the configuration into a string
-        let data: String =
-            std::fs::read_to_string(local_tmp_dir.path().join(configuration_filename))?;
-
-        // Parse the lines
-        let mut actual: Vec<String> = data.lines().map(|x| x.to_owned()).collect::<Vec<String>>();
-        // Sort the lines
-        actual.sort();
-
-        // Do the same with the payload
-        let mut payload = payload
-            .lines()
-            .map(|x| x.to_owned())
-            .collect::<Vec<String>>();
-        payload.sort();
-
-        log::trace!(
-            "Comparing:\n==========\n{}\n==========\n{}\n==========",
-            actual.join("\n"),
-            payload.join("\n")
-        );
+                StepResult::from_output(step, self.exec_as_root(&command)?)
+            }
+        };
 
-        // Comparer the actual data with the payload
-        if actual != payload {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Configuration was not updated!",
-            )));
+        Ok(result)
+    }
+}
+
+// Paths shared by the steps of an upload
+#[derive(Default)]
+struct UploadState {
+    // Where the transfer step left the new map, unique so concurrent uploads don't mix
+    uploaded_path: String,
+    // Copy of the live map made by the backup step
+    backup_path: String,
+    // The map found on the server when it changed since it was downloaded
+    remote_changed: Option<Snapshot>,
+    // The map left on the server by the verify step
+    installed: Option<Snapshot>,
+}
+
+// Command that replaces target with source: source is copied next to target with the same owner and mode, then renamed
+// over it, so postfix never reads a half written map. cp -p copies the owner and mode on GNU and BSD systems alike, a
+// new map belongs to root with mode 0644
+fn install_command(source: &str, target: &str) -> String {
+    format!(
+        "(target={} && tmp=$(mktemp \"$target.XXXXXX\") && trap 'rm -f \"$tmp\"' EXIT && \
+        if [ -e \"$target\" ]; then cp -p \"$target\" \"$tmp\"; else chown 0:0 \"$tmp\" && chmod 644 \"$tmp\"; fi && \
+        cat {} > \"$tmp\" && mv -f \"$tmp\" \"$target\")",
+        shell_quote(target),
+        shell_quote(source),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_prompts_are_told_from_output() {
+        let cases = [
+            (
+                Escalation::Sudo,
+                "[email-forward-manager] password:\n",
+                true,
+            ),
+            (Escalation::Sudo, "Password:", false),
+            (Escalation::Su, "Password: ", true),
+            (Escalation::Su, "Contraseña:", true),
+            (Escalation::Doas, "doas (jdoe@mx) password:", true),
+            (Escalation::Doas, "doas (jdoe@mx\n) password:", false),
+            // A line of the command ending in a colon isn't a prompt
+            (
+                Escalation::Su,
+                "postfix/postfix-script: warning: not owned by root:",
+                false,
+            ),
+            (Escalation::Su, "Password:\npostfix check failed", false),
+            (Escalation::Root, "Password:", false),
+            (Escalation::Root, "[email-forward-manager] password:", false),
+        ];
+
+        for (escalation, output, prompt) in cases {
+            assert_eq!(
+                is_password_prompt(output, escalation),
+                prompt,
+                "{escalation}: {output:?}"
+            );
         }
+    }
 
-        Ok(())
+    #[cfg(unix)]
+    #[test]
+    fn install_command_quotes_paths_and_keeps_the_mode() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let directory = std::env::temp_dir().join(format!(
+            "email-forward-manager {} it's \"here\"",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&directory).unwrap();
+        let source = directory.join("new 'map'");
+        let target = directory.join("live \"map\" $HOME");
+        let install = || {
+            std::process::Command::new("sh")
+                .arg("-c")
+                .arg(install_command(
+                    &source.to_string_lossy(),
+                    &target.to_string_lossy(),
+                ))
+                .status()
+                .unwrap()
+                .success()
+        };
+
+        // A new map belongs to root with mode 0644, which needs root to be tried
+        std::fs::write(&source, "a b\n").unwrap();
+        if std::fs::metadata(&source).unwrap().uid() == 0 {
+            assert!(install());
+            let metadata = std::fs::metadata(&target).unwrap();
+            assert_eq!((metadata.uid(), metadata.gid()), (0, 0));
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o644);
+            assert_eq!(std::fs::read_to_string(&target).unwrap(), "a b\n");
+        }
+
+        // An existing map keeps its mode
+        std::fs::write(&target, "old\n").unwrap();
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o600)).unwrap();
+        std::fs::write(&source, "c d\n").unwrap();
+        assert!(install());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "c d\n");
+        let metadata = std::fs::metadata(&target).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+        // The temporary copy was renamed, nothing is left behind
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 2);
+        std::fs::remove_dir_all(&directory).unwrap();
     }
 }
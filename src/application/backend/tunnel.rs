@@ -0,0 +1,105 @@
+use std::{
+    error::Error,
+    io::{ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+use ssh2::{Channel, Session};
+
+// Size of the buffers moving data between the local socket and the channel
+const BUFFER_SIZE: usize = 16 * 1024;
+
+// Open a connection to addr:port through a direct-tcpip channel of an authenticated session.
+// libssh2 needs a real socket for the next session, so the channel is bridged to a local tcp connection
+// by a thread that keeps the jump session alive until the connection is closed
+pub fn forward(session: Session, addr: &str, port: u16) -> Result<TcpStream, Box<dyn Error>> {
+    let channel = session.channel_direct_tcpip(addr, port, None)?;
+
+    let listener = TcpListener::bind(("127.0.0.1", 0))?;
+    let client = TcpStream::connect(listener.local_addr()?)?;
+
+    // Another local process could connect to the listener first, only take our own connection
+    let local = loop {
+        let (stream, peer) = listener.accept()?;
+        if peer == client.local_addr()? {
+            break stream;
+        }
+        log::warn!("Rejected an unexpected connection to the tunnel from {peer}");
+    };
+
+    let target = format!("{addr}:{port}");
+    thread::spawn(move || {
+        if let Err(error) = pump(&session, channel, local) {
+            log::warn!("Tunnel to {target} closed: {error}");
+        }
+    });
+
+    Ok(client)
+}
+
+// Move data both ways until either side is closed
+fn pump(session: &Session, mut channel: Channel, mut local: TcpStream) -> std::io::Result<()> {
+    session.set_blocking(false);
+    local.set_nonblocking(true)?;
+
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut to_remote: Vec<u8> = vec![];
+    let mut to_local: Vec<u8> = vec![];
+
+    loop {
+        let mut idle = true;
+
+        if to_remote.is_empty() {
+            match local.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => to_remote.extend_from_slice(&buffer[..read]),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+                Err(error) => return Err(error),
+            }
+        }
+        if !to_remote.is_empty() {
+            match channel.write(&to_remote) {
+                Ok(written) => {
+                    to_remote.drain(..written);
+                    idle = false;
+                }
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+                Err(error) => return Err(error),
+            }
+        }
+
+        if to_local.is_empty() {
+            match channel.read(&mut buffer) {
+                Ok(0) if channel.eof() => break,
+                Ok(read) => to_local.extend_from_slice(&buffer[..read]),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+                Err(error) => return Err(error),
+            }
+        }
+        if !to_local.is_empty() {
+            match local.write(&to_local) {
+                Ok(written) => {
+                    to_local.drain(..written);
+                    idle = false;
+                }
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+                Err(error) => return Err(error),
+            }
+        }
+
+        // Nothing moved, don't spin. Keepalives are sent while idle, when they are due
+        if idle {
+            let _ = session.keepalive_send();
+            thread::sleep(Duration::from_millis(2));
+        }
+    }
+
+    // Flush what the remote side sent before closing, the local side may still be reading it
+    let _ = local.set_nonblocking(false);
+    let _ = local.write_all(&to_local);
+    session.set_blocking(true);
+    let _ = channel.close();
+    Ok(())
+}
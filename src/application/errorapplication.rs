@@ -6,6 +6,7 @@ pub struct ErrorApplication {
 }
 
 impl ErrorApplication {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(message: String) -> Box<dyn App> {
         Box::new(Self { message })
     }
@@ -1,8 +1,11 @@
 use std::{error::Error, process::exit};
 
-use egui::{Context, Vec2};
+use egui::{Color32, Context, RichText, Vec2};
 
-use super::{backend::server::AuthStatus, Application, QueryMessage};
+use super::{
+    backend::server::{AuthMethod, AuthStatus, Escalation},
+    Application, QueryMessage,
+};
 
 // Implementation for the application's login ui
 impl Application {
@@ -11,8 +14,37 @@ impl Application {
         ctx: &Context,
         frame: &mut eframe::Frame,
     ) -> Result<(), Box<dyn Error>> {
-        // Resize the window for the login view
-        frame.set_window_size(Vec2::new(400.0, 135.0));
+        // Unknown host keys must be confirmed before anything else
+        if !self.host_key_prompts.is_empty() {
+            return self.draw_host_key_prompt(ctx, frame);
+        }
+
+        // Only ask for the secrets the servers need: servers using keys don't take a password
+        // sudo and doas ask for the login password too, it may stay empty if they don't ask for one
+        let needs_password = self.needs_auth_method(AuthMethod::Password)
+            || self.needs_escalation(Escalation::Sudo)
+            || self.needs_escalation(Escalation::Doas);
+        let needs_passphrase = self.needs_auth_method(AuthMethod::Key);
+        let needs_root_password = self.needs_escalation(Escalation::Su);
+
+        // Resize the window for the login view, it grows with the number of fields
+        // and with the errors of the last attempt
+        let fields =
+            1 + needs_password as usize + needs_passphrase as usize + needs_root_password as usize;
+        // The maps of a host share its login, it's shown once
+        let hosts = self.hosts();
+        let errors = hosts
+            .iter()
+            .filter(|(_, maps)| self.servers[maps[0]].auth_error.is_some())
+            .count();
+        let tunneled = hosts
+            .iter()
+            .filter(|(_, maps)| !self.servers[maps[0]].hops.is_empty())
+            .count();
+        frame.set_window_size(Vec2::new(
+            400.0,
+            60.0 + 25.0 * fields as f32 + 50.0 * errors as f32 + 20.0 * tunneled as f32,
+        ));
 
         egui::TopBottomPanel::top("topbar").show(ctx, |ui| {
             ui.horizontal(|ui| {
@@ -34,24 +66,41 @@ impl Application {
             });
 
             // Password text input
-            ui.horizontal(|ui| {
-                let label_width = ui.label("Contraseña:").rect.width();
-                ui.add(
-                    egui::TextEdit::singleline(&mut self.password)
-                        .desired_width(available_width - label_width)
-                        .password(true),
-                );
-            });
+            if needs_password {
+                ui.horizontal(|ui| {
+                    let label_width = ui.label("Contraseña:").rect.width();
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.password)
+                            .desired_width(available_width - label_width)
+                            .password(true),
+                    );
+                });
+            }
 
-            // Root password text input
-            ui.horizontal(|ui| {
-                let label_width = ui.label("Contraseña del root:").rect.width();
-                ui.add(
-                    egui::TextEdit::singleline(&mut self.root_password)
-                        .desired_width(available_width - label_width)
-                        .password(true),
-                );
-            });
+            // Private key passphrase text input, it stays empty for keys without passphrase
+            if needs_passphrase {
+                ui.horizontal(|ui| {
+                    let label_width = ui.label("Frase de la clave:").rect.width();
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.passphrase)
+                            .desired_width(available_width - label_width)
+                            .hint_text("vacía si la clave no tiene")
+                            .password(true),
+                    );
+                });
+            }
+
+            // Root password text input, only servers escalating with su need it
+            if needs_root_password {
+                ui.horizontal(|ui| {
+                    let label_width = ui.label("Contraseña del root:").rect.width();
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.root_password)
+                            .desired_width(available_width - label_width)
+                            .password(true),
+                    );
+                });
+            }
 
             ui.add_space(10.0);
 
@@ -63,8 +112,8 @@ impl Application {
                         // If there is a login in progress show a spinner or checkmark for each instance
 
                         ui.horizontal(|ui| {
-                            for server in self.servers.iter() {
-                                match server.auth_status {
+                            for (_, maps) in hosts.iter() {
+                                match self.servers[maps[0]].auth_status {
                                     AuthStatus::Unknown => {
                                         ui.label("❓");
                                     }
@@ -74,7 +123,7 @@ impl Application {
                                     AuthStatus::Authenticated => {
                                         ui.label("✅");
                                     }
-                                    AuthStatus::InProgress => {
+                                    AuthStatus::InProgress | AuthStatus::Reconnecting => {
                                         ui.spinner();
                                     }
                                 };
@@ -83,31 +132,14 @@ impl Application {
                     } else {
                         // Add the login button
                         if ui.button("Ok").clicked() {
-                            for server in self.servers.iter_mut() {
-                                if server.auth_status == AuthStatus::Authenticated {
-                                    continue;
-                                }
-
-                                server.auth_status = AuthStatus::InProgress;
-                            }
-
                             // Send a query to the backend
-                            let _ = self.tx.send(QueryMessage::Authenticate {
-                                username: self.username.clone(),
-                                password: self.password.clone(),
-                                servers: self
-                                    .servers
+                            self.authenticate_servers(
+                                self.servers
                                     .iter()
-                                    .filter_map(|x| {
-                                        if x.auth_status == AuthStatus::Authenticated {
-                                            None
-                                        } else {
-                                            Some(x.clone())
-                                        }
-                                    })
+                                    .filter(|x| x.auth_status != AuthStatus::Authenticated)
+                                    .cloned()
                                     .collect(),
-                                root_password: self.root_password.clone(),
-                            });
+                            );
                         }
                     }
                 });
@@ -117,8 +149,101 @@ impl Application {
                     exit(0);
                 }
             });
+
+            // Every login of the servers behind jump hosts, so the user can tell which one failed
+            for server in hosts.iter().map(|(_, maps)| &self.servers[maps[0]]) {
+                if server.hops.is_empty() {
+                    continue;
+                }
+                let hops = server
+                    .hops
+                    .iter()
+                    .map(|hop| {
+                        let icon = match hop.status {
+                            AuthStatus::Unknown => "❓",
+                            AuthStatus::Failed => "❌",
+                            AuthStatus::Authenticated => "✅",
+                            AuthStatus::InProgress | AuthStatus::Reconnecting => "⏳",
+                        };
+                        format!("{} {icon}", hop.name)
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" → ");
+                ui.small(hops);
+            }
+
+            // Why the last attempt failed, a changed host key must be read before trying again
+            for (host, maps) in hosts.iter() {
+                if let Some(error) = &self.servers[maps[0]].auth_error {
+                    ui.colored_label(Color32::RED, format!("{host}: {error}"));
+                }
+            }
         });
 
         Ok(())
     }
+
+    // Ask the user to confirm the fingerprint of a host seen for the first time (trust on first use)
+    fn draw_host_key_prompt(
+        &mut self,
+        ctx: &Context,
+        frame: &mut eframe::Frame,
+    ) -> Result<(), Box<dyn Error>> {
+        frame.set_window_size(Vec2::new(400.0, 230.0));
+
+        let (_, host_key) = self.host_key_prompts[0].clone();
+        let mut trust = false;
+        let mut reject = false;
+
+        egui::TopBottomPanel::top("topbar").show(ctx, |ui| {
+            ui.heading("Servidor desconocido");
+        });
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.label(format!(
+                "Es la primera vez que se conecta a {}:{}. Compruebe con el administrador que la huella de la clave del servidor es:",
+                host_key.addr, host_key.port
+            ));
+            ui.add_space(10.0);
+            ui.label(RichText::new(&host_key.algorithm).monospace());
+            ui.label(RichText::new(&host_key.fingerprint).monospace().strong());
+            ui.add_space(10.0);
+
+            ui.horizontal(|ui| {
+                trust = ui.button("Confiar y conectar").clicked();
+                reject = ui.button("Cancelar").clicked();
+            });
+        });
+
+        if trust || reject {
+            // Every map in the same host is waiting for the same key
+            let (answered, pending) = self
+                .host_key_prompts
+                .drain(..)
+                .partition(|(_, key)| *key == host_key);
+            self.host_key_prompts = pending;
+            let servers: Vec<_> = answered.into_iter().map(|(server, _)| server).collect();
+
+            if trust {
+                // They log in again when the backend answers, after storing the key
+                for server in servers.iter() {
+                    if let Some(s) = self.get_server(server) {
+                        s.auth_status = AuthStatus::InProgress;
+                    }
+                }
+                let _ = self
+                    .tx
+                    .send(QueryMessage::TrustHostKey { servers, host_key });
+            } else {
+                for server in servers {
+                    if let Some(s) = self.get_server(&server) {
+                        s.auth_status = AuthStatus::Failed;
+                        s.auth_error = Some("Host key not trusted".to_owned());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
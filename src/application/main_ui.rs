@@ -1,10 +1,25 @@
-use std::{borrow::BorrowMut, error::Error};
+use std::error::Error;
 
 use eframe::Frame;
-use egui::{Color32, Context, Vec2};
+use egui::{Color32, Context, RichText, Ui, Vec2};
 
 use crate::{
-    application::{backend::server::UsersStatus, QueryMessage},
+    application::{
+        backend::{
+            lock::LockStatus,
+            mapformat::{
+                regexp::{Dialect, RegexpFormat},
+                MapKind,
+            },
+            merge::Side,
+            remotecommand::StepResult,
+            resolver::{Expansion, Resolver},
+            reverseindex::{remove_destination, removes_entry, ReverseIndex},
+            server::{AuthStatus, UsersStatus},
+        },
+        value_editors::{access_editor, transport_editor, ValueEditor},
+        QueryMessage,
+    },
     cache_utils::{get_cache_value, set_cache_value},
 };
 
@@ -12,14 +27,32 @@ use super::Application;
 
 // Possible modifications the user can make to the data using the interface
 enum Modification {
-    // Update the whole email and redirection entry
-    // This also results in insertion if the email is not already in the list
+    // Add a new email at the end of the list, without redirections
+    AddEmail(String),
+    // Update the redirections of the entry at a position. Entries are addressed by position because the rules of
+    // ordered tables repeat keys (endif)
     UpdateEmail {
-        email: String,
+        index: usize,
         redirections: Vec<String>,
     },
-    // Remove the whole email
-    RemoveEmail(String),
+    // Change the email of an entry, keeping its redirections and its position in the file
+    Rename {
+        index: usize,
+        new_email: String,
+    },
+    // Remove the entry at a position
+    RemoveEmail(usize),
+    // Remove a destination from an entry of any server, the entry is removed if it's left empty
+    RemoveDestination {
+        server: usize,
+        email: String,
+        destination: String,
+    },
+    // Move an entry to another position, for tables whose rules are tried in order
+    Move {
+        from: usize,
+        to: usize,
+    },
 }
 
 // Implementation for the application's main ui
@@ -41,27 +74,47 @@ impl Application {
                 egui::ScrollArea::horizontal()
                     .id_source("servers")
                     .show(ui, |ui| {
-                        // Iterate over the servers and add the buttons
-                        for (index, server) in self.servers.iter().enumerate() {
-                            // Show in green the selected server's button
-                            ui.visuals_mut().override_text_color = if selected_server == index {
-                                Some(Color32::GREEN)
-                            } else {
-                                None
+                        // The maps grouped under their host, they share its session
+                        for (host, maps) in self.hosts() {
+                            // Sessions that died show whether they are being logged in again or couldn't be
+                            let first = &self.servers[maps[0]];
+                            let (label, tooltip) = match (&first.auth_status, &first.auth_error) {
+                                (AuthStatus::Reconnecting, _) => {
+                                    (format!("🔄 {}:", host.addr), "Reconectando...".to_owned())
+                                }
+                                (AuthStatus::Failed, Some(error)) => {
+                                    (format!("⚠ {}:", host.addr), error.clone())
+                                }
+                                _ => (format!("{}:", host.addr), host.to_string()),
                             };
+                            ui.visuals_mut().override_text_color = None;
+                            ui.label(label).on_hover_text(tooltip);
 
-                            // Add the button
-                            if ui
-                                .small_button(&server.addr)
-                                // Tooltip for the button(the server's configuration path)
-                                .on_hover_text(&server.config_path)
-                                .clicked()
-                            {
-                                // Handle click:
-                                // Set the variable and save it to the cache
-                                selected_server = index;
-                                set_cache_value("current_server", ui, selected_server);
+                            for index in maps {
+                                let server = &self.servers[index];
+
+                                // Show in green the selected server's button
+                                ui.visuals_mut().override_text_color = if selected_server == index
+                                {
+                                    Some(Color32::GREEN)
+                                } else {
+                                    None
+                                };
+
+                                // Add the button
+                                if ui
+                                    .small_button(server.map_name())
+                                    // Tooltip for the button(the server's configuration path)
+                                    .on_hover_text(&server.config_path)
+                                    .clicked()
+                                {
+                                    // Handle click:
+                                    // Set the variable and save it to the cache
+                                    selected_server = index;
+                                    set_cache_value("current_server", ui, selected_server);
+                                }
                             }
+                            ui.separator();
                         }
                     });
             });
@@ -70,20 +123,139 @@ impl Application {
             // so there can be composed actions where more than one entry is modified(removed/inserted)
             let mut modifications: Vec<Modification> = vec![];
 
+            // While someone else holds the lock the map is read only, their upload would be refused anyway
+            let editable = !matches!(self.servers[selected_server].lock, LockStatus::Theirs(_));
+
+            // Find every entry that redirects to an address in all the servers, so it can be removed everywhere at once
+            ui.collapsing("Buscar quién redirige a una dirección", |ui| {
+                let mut destination: String = get_cache_value("reverse_lookup", ui, None);
+                if ui
+                    .add(egui::TextEdit::singleline(&mut destination).hint_text("user@example.com"))
+                    .changed()
+                {
+                    set_cache_value("reverse_lookup", ui, destination.clone());
+                }
+
+                let destination = destination.trim();
+                if destination.is_empty() {
+                    return;
+                }
+
+                // Only the loaded tables whose values are destinations
+                let mut found: Vec<(usize, String)> = vec![];
+                for (index, server) in self.servers.iter().enumerate().filter(|(_, server)| {
+                    server.auth_status == AuthStatus::Authenticated
+                        && server.users_status == UsersStatus::Idle
+                        && server.format.format().recursive()
+                }) {
+                    for key in ReverseIndex::new(&server.users).forwarders(destination) {
+                        found.push((index, key.to_string()));
+                    }
+                }
+
+                if found.is_empty() {
+                    ui.label("Ninguna entrada redirige a esa dirección");
+                    return;
+                }
+
+                // Entries that only redirect to the address are removed with it
+                let emptied: Vec<bool> = found
+                    .iter()
+                    .map(|(index, email)| {
+                        removes_entry(&self.servers[*index].users, email, destination)
+                    })
+                    .collect();
+
+                let confirming: bool = get_cache_value("confirm_remove_everywhere", ui, None);
+                ui.add_enabled_ui(!some_server_is_busy, |ui| {
+                    for ((index, email), emptied) in found.iter().zip(emptied.iter()) {
+                        ui.horizontal(|ui| {
+                            if ui.small_button("❌").clicked() {
+                                modifications.push(Modification::RemoveDestination {
+                                    server: *index,
+                                    email: email.clone(),
+                                    destination: destination.to_owned(),
+                                });
+                            }
+                            let server = &self.servers[*index];
+                            ui.label(format!("{server} ({}): {email}", server.config_path));
+                            if *emptied {
+                                ui.colored_label(Color32::YELLOW, "(se elimina la entrada)");
+                            }
+                        });
+                    }
+
+                    if !confirming {
+                        if ui.button("Eliminar de todas").clicked() {
+                            set_cache_value("confirm_remove_everywhere", ui, true);
+                        }
+                        return;
+                    }
+
+                    // Removing the address from everywhere asks first, saying which entries disappear with it
+                    let mut keep: bool = get_cache_value("keep_emptied_entries", ui, None);
+                    if emptied.contains(&true) {
+                        ui.colored_label(
+                            Color32::YELLOW,
+                            "Estas entradas solo redirigen a esa dirección y se eliminarán por completo:",
+                        );
+                        for ((index, email), _) in
+                            found.iter().zip(emptied.iter()).filter(|(_, emptied)| **emptied)
+                        {
+                            let server = &self.servers[*index];
+                            ui.label(format!("{server} ({}): {email}", server.config_path));
+                        }
+                        if ui
+                            .checkbox(&mut keep, "Conservarlas tal como están")
+                            .changed()
+                        {
+                            set_cache_value("keep_emptied_entries", ui, keep);
+                        }
+                    }
+
+                    ui.horizontal(|ui| {
+                        if ui.button("Confirmar").clicked() {
+                            modifications.extend(
+                                found
+                                    .iter()
+                                    .zip(emptied.iter())
+                                    .filter(|(_, emptied)| !keep || !**emptied)
+                                    .map(|((index, email), _)| Modification::RemoveDestination {
+                                        server: *index,
+                                        email: email.clone(),
+                                        destination: destination.to_owned(),
+                                    }),
+                            );
+                            set_cache_value("confirm_remove_everywhere", ui, false);
+                        }
+                        if ui.button("Cancelar").clicked() {
+                            set_cache_value("confirm_remove_everywhere", ui, false);
+                        }
+                    });
+                });
+                ui.small("Los cambios hay que guardarlos en cada servidor");
+            });
+
             // This is scoped so the self.servers borrow is released after the scope is exited
             {
                 if self.servers[selected_server].users_status != UsersStatus::Unknown {
                     ui.separator();
 
+                    // Entries the format considers invalid (empty entries included), they must be fixed before saving
+                    let validation_errors = self.servers[selected_server]
+                        .validation_errors()
+                        .to_vec();
+
+                    // Nothing can be uploaded while a merge is pending or someone else holds the lock
+                    let can_upload = !some_server_is_busy
+                        && self.servers[selected_server].merge.is_none()
+                        && !matches!(self.servers[selected_server].lock, LockStatus::Theirs(_));
+
                     // A little heading
                     ui.horizontal(|ui| {
                         // Show a button that allows the user to upload the configuration to the server
                         ui.add_enabled_ui(
-                            !some_server_is_busy
-                                && !self.servers[selected_server]
-                                    .users
-                                    .iter()
-                                    .any(|(_, redirections)| redirections.is_empty()),
+                            can_upload && validation_errors.is_empty(),
                             |ui| {
                                 if ui.button("Guardar en el servidor").clicked() {
                                     log::trace!("Saving...");
@@ -109,181 +281,725 @@ impl Application {
                             ui.spinner();
                         }
                     });
-                }
 
-                // Get the redirections in a variable so it's easyer to type
-                let server_redirections = &self.servers[selected_server].users;
-
-                // Show the items in a vertical scroll area, so it's free to grow as needed
-                egui::ScrollArea::vertical().show(ui, |ui| {
-                    // Get the redirection keys and sort them, so it's uniform and consistent among iterations
-                    let mut keys: Vec<&String> = server_redirections.keys().collect();
-                    keys.sort();
-
-                    // Iterate over the keys
-                    keys.into_iter().for_each(|mail| {
-                        // Get the redirections for that key
-                        let redirections = server_redirections.get(mail).unwrap();
-
-                        // Show the data grouped(This is more appealing)
-                        ui.collapsing(mail, |ui| {
-                            // Make the group allocate the whole horizontal space, so it's uniform
-                            ui.allocate_space(Vec2::new(ui.available_width(), 0.0));
-                            // Horizontal widget to hold the email and the delete button
+                    // Show who is editing the map, someone else's lock can be broken when it was left behind
+                    let mut break_lock = false;
+                    match &self.servers[selected_server].lock {
+                        LockStatus::Unlocked => {}
+                        LockStatus::Requested => {
+                            ui.label("🔒 Bloqueando la tabla...");
+                        }
+                        LockStatus::Ours(_) => {
+                            ui.label("🔒 Editando, los demás no pueden guardar hasta que guardes");
+                        }
+                        LockStatus::Theirs(owner) => {
                             ui.horizontal(|ui| {
-                                let mut email: String = get_cache_value(
-                                    &(mail.to_owned() + "e"),
-                                    ui,
-                                    Some(mail.to_owned()),
+                                ui.colored_label(
+                                    Color32::YELLOW,
+                                    format!(
+                                        "🔒 Bloqueada por {owner} desde {}, solo lectura",
+                                        owner.since
+                                    ),
                                 );
-                                if ui.text_edit_singleline(&mut email).changed() {
-                                    set_cache_value(&(mail.to_owned() + "e"), ui, email.clone());
-                                }
+                                break_lock = ui
+                                    .small_button("Romper bloqueo")
+                                    .on_hover_text(
+                                        "Solo si el bloqueo quedó abandonado, sus cambios se perderían",
+                                    )
+                                    .clicked();
+                            });
+                        }
+                    }
+                    if break_lock {
+                        self.servers[selected_server].lock = LockStatus::Requested;
+                        let _ = self.tx.send(QueryMessage::BreakLock(
+                            self.servers[selected_server].clone(),
+                        ));
+                    }
+
+                    // The map changed on the server since it was downloaded: show the three versions of every conflicting
+                    // entry until the user picks one
+                    let mut resolution = None;
+                    let mut apply = false;
+                    let mut discard = false;
+                    if let Some(merge) = &self.servers[selected_server].merge {
+                        ui.colored_label(
+                            Color32::YELLOW,
+                            "⚠ La tabla cambió en el servidor desde que se descargó",
+                        );
+                        if merge.whole_file {
+                            ui.label(
+                                "Las reglas de esta tabla dependen de su orden y no se pueden fusionar una a una",
+                            );
+                        }
+                        if merge.remote_changes > 0 {
+                            ui.label(format!(
+                                "{} cambios del servidor se integran sin conflicto",
+                                merge.remote_changes
+                            ));
+                        }
+
+                        for (index, conflict) in merge.conflicts.iter().enumerate() {
+                            egui::CollapsingHeader::new(format!("⚠ {}", conflict.key))
+                                .id_source(("conflict", selected_server, &conflict.key))
+                                .default_open(true)
+                                .show(ui, |ui| {
+                                    for (side, label, button) in [
+                                        (Side::Base, "Original", "Usar original"),
+                                        (Side::Remote, "Servidor", "Usar servidor"),
+                                        (Side::Local, "Local", "Usar local"),
+                                    ] {
+                                        ui.horizontal(|ui| {
+                                            if ui.small_button(button).clicked() {
+                                                resolution = Some((index, side));
+                                            }
+
+                                            let values = conflict
+                                                .values(side)
+                                                .map(|values| values.join(", "))
+                                                .unwrap_or("(no existe)".to_owned());
+                                            ui.label(format!("{label}: {values}"));
+                                        });
+                                    }
+                                });
+                        }
+
+                        ui.add_enabled_ui(editable, |ui| ui.horizontal(|ui| {
+                            if merge.whole_file {
+                                apply = ui
+                                    .button("Sobrescribir con la mía")
+                                    .on_hover_text(
+                                        "Los cambios hechos en el servidor se perderán, luego hay que guardar",
+                                    )
+                                    .clicked();
+                                discard = ui.button("Usar la del servidor").clicked();
+                                return;
+                            }
+
+                            ui.add_enabled_ui(merge.conflicts.is_empty(), |ui| {
+                                apply = ui
+                                    .button("Aplicar fusión")
+                                    .on_hover_text(
+                                        "Usa la tabla del servidor con tus cambios, luego hay que guardar",
+                                    )
+                                    .clicked();
+                            });
+                            discard = ui.button("Descartar mis cambios").clicked();
+                        }));
+                    }
+
+                    let server = &mut self.servers[selected_server];
+                    if let (Some((index, side)), Some(merge)) = (resolution, &mut server.merge) {
+                        merge.resolve(index, side);
+                    }
+                    if apply || discard {
+                        if let Some(merge) = server.merge.take() {
+                            log::trace!("Merge applied: {apply}");
+                            server.set_users(if apply {
+                                merge.merged
+                            } else {
+                                merge.remote.document.clone()
+                            });
+                            server.snapshot = merge.remote;
+                        }
+                    }
+                    if apply {
+                        self.lock_for_editing(selected_server);
+                    }
+
+                    // Show what the uploads did, the last one opened when it failed so the error is visible
+                    let operations = &self.servers[selected_server].operations;
+                    if !operations.is_empty() {
+                        ui.collapsing("Registro de operaciones", |ui| {
+                            for (index, operation) in operations.iter().enumerate().rev() {
+                                let icon = if operation.success() { "✅" } else { "❌" };
+                                let rollback = if operation.rolled_back() {
+                                    ", se restauró la tabla anterior"
+                                } else {
+                                    ""
+                                };
+                                egui::CollapsingHeader::new(format!(
+                                    "{icon} {} Guardado{rollback}",
+                                    operation.time
+                                ))
+                                .id_source(("operation", selected_server, index))
+                                .default_open(index == operations.len() - 1 && !operation.success())
+                                .show(ui, |ui| draw_steps(ui, &operation.steps));
+                            }
+                        });
+                    }
 
-                                if ui.small_button("Cambiar").clicked() {
-                                    modifications.push(Modification::UpdateEmail {
-                                        email,
-                                        redirections: redirections.clone(),
+                    // Backups of the map on the server: any of them can be compared with the map and restored through a
+                    // normal upload
+                    let mut list = false;
+                    let mut prune = false;
+                    let mut compare = None;
+                    let mut restore = false;
+                    let mut close = false;
+                    let server = &self.servers[selected_server];
+                    ui.collapsing("Copias de seguridad", |ui| {
+                        ui.horizontal(|ui| {
+                            list = ui.small_button("Actualizar").clicked();
+                            if server.keep_backups.is_some() || server.keep_backups_days.is_some() {
+                                prune = ui
+                                    .small_button("Borrar las viejas")
+                                    .on_hover_text("Según keep_backups y keep_backups_days")
+                                    .clicked();
+                            }
+                        });
+
+                        match &server.backups {
+                            // List them the first time the panel is opened
+                            None => list = true,
+                            Some(backups) if backups.is_empty() => {
+                                ui.label("No hay copias");
+                            }
+                            Some(backups) => {
+                                for backup in backups.iter() {
+                                    ui.horizontal(|ui| {
+                                        ui.label(backup.label());
+                                        if ui.small_button("Comparar").clicked() {
+                                            compare = Some(backup.path.clone());
+                                        }
                                     });
-                                    modifications.push(Modification::RemoveEmail(mail.to_owned()));
-                                }
-                                // ui.add_space(40.0);
-                                // Add the delete button
-                                if ui.small_button("❌").clicked() {
-                                    log::trace!("Removing entry");
-                                    modifications.push(Modification::RemoveEmail(mail.to_owned()));
                                 }
+                            }
+                        }
+
+                        // What restoring the backup would undo
+                        if let Some(view) = &server.backup_view {
+                            ui.separator();
+                            ui.label(format!("Restaurar {} deshace estos cambios:", view.path));
+
+                            if view.differences.is_empty() {
+                                ui.label("La copia es igual a la tabla actual");
+                            }
+                            for difference in view.differences.iter() {
+                                match (&difference.old, &difference.new) {
+                                    (None, Some(new)) => ui.colored_label(
+                                        Color32::GREEN,
+                                        format!("+ {}: {}", difference.key, new.join(", ")),
+                                    ),
+                                    (Some(old), None) => ui.colored_label(
+                                        Color32::RED,
+                                        format!("- {}: {}", difference.key, old.join(", ")),
+                                    ),
+                                    (old, new) => ui.colored_label(
+                                        Color32::YELLOW,
+                                        format!(
+                                            "~ {}: {} → {}",
+                                            difference.key,
+                                            old.as_deref().unwrap_or_default().join(", "),
+                                            new.as_deref().unwrap_or_default().join(", ")
+                                        ),
+                                    ),
+                                };
+                            }
+
+                            ui.horizontal(|ui| {
+                                ui.add_enabled_ui(can_upload, |ui| {
+                                    restore = ui
+                                        .button("Restaurar esta copia")
+                                        .on_hover_text(
+                                            "Si se guarda, reemplaza también los cambios sin guardar",
+                                        )
+                                        .clicked();
+                                });
+                                close = ui.button("Cerrar").clicked();
                             });
+                        }
+                    });
 
-                            ui.add_space(10.0);
-                            ui.label("Redirecciones");
+                    if list {
+                        self.servers[selected_server].backups = Some(vec![]);
+                        let _ = self.tx.send(QueryMessage::ListBackups(
+                            self.servers[selected_server].clone(),
+                        ));
+                    }
+                    if prune {
+                        let _ = self.tx.send(QueryMessage::PruneBackups(
+                            self.servers[selected_server].clone(),
+                        ));
+                    }
+                    if let Some(path) = compare {
+                        let _ = self.tx.send(QueryMessage::DownloadBackup {
+                            server: self.servers[selected_server].clone(),
+                            path,
+                        });
+                    }
+                    if restore {
+                        if let Some(view) = self.servers[selected_server].backup_view.take() {
+                            log::trace!("Restoring {}", view.path);
 
-                            // Iterate over the redirections, adding the entries
-                            for redir in redirections {
-                                // Horizontal widget to hold the item's label and the delete button
+                            // The backup goes through the same checks as any upload, the local map is kept until it
+                            // succeeds
+                            self.lock_for_editing(selected_server);
+                            let server = &mut self.servers[selected_server];
+                            let mut upload = server.clone();
+                            upload.set_users(view.document.clone());
+                            server.restoring = Some(view.document);
+                            server.users_status = UsersStatus::Uploading;
+                            let _ = self.tx.send(QueryMessage::UpdateVirtualUsers(upload));
+                        }
+                    }
+                    if close {
+                        self.servers[selected_server].backup_view = None;
+                    }
+
+                    // List the invalid entries so the user knows why the data can't be saved
+                    if !validation_errors.is_empty() {
+                        ui.visuals_mut().override_text_color = Some(Color32::RED);
+                        ui.collapsing(
+                            format!("⚠ {} problemas impiden guardar", validation_errors.len()),
+                            |ui| {
+                                for error in validation_errors.iter() {
+                                    ui.label(error.to_string());
+                                }
+                            },
+                        );
+                        ui.visuals_mut().override_text_color = None;
+                    }
+
+                    // Show the duplicated entries found when the map was loaded, and offer to merge them
+                    let report = &self.servers[selected_server].normalization;
+                    if !report.is_empty() {
+                        let mut merge = false;
+                        let mut dismiss = false;
+
+                        ui.visuals_mut().override_text_color = Some(Color32::YELLOW);
+                        ui.collapsing("⚠ Hay entradas duplicadas o ignoradas en el servidor", |ui| {
+                            for key in report.duplicate_keys.iter() {
+                                ui.label(format!("'{key}' aparece más de una vez"));
+                            }
+                            for keys in report.case_collisions.iter() {
+                                ui.label(format!(
+                                    "Solo difieren en mayúsculas: {}",
+                                    keys.join(", ")
+                                ));
+                            }
+                            for (key, destinations) in report.repeated_destinations.iter() {
+                                ui.label(format!("'{key}' repite: {}", destinations.join(", ")));
+                            }
+                            for warning in report.ignored_lines.iter() {
+                                ui.label(format!(
+                                    "Postfix ignora la línea {}: {}",
+                                    warning.line, warning.message
+                                ));
+                            }
+                        });
+                        ui.visuals_mut().override_text_color = None;
+
+                        ui.add_enabled_ui(editable, |ui| ui.horizontal(|ui| {
+                            merge = ui
+                                .small_button("Fusionar")
+                                .on_hover_text(
+                                    "Une las entradas en la primera aparición, como hace postfix",
+                                )
+                                .clicked();
+                            dismiss = ui.small_button("Ignorar").clicked();
+                        }));
+
+                        if merge {
+                            log::trace!("Merging duplicated entries");
+                            self.servers[selected_server].users_mut().normalize();
+                            self.lock_for_editing(selected_server);
+                        }
+                        if merge || dismiss {
+                            self.servers[selected_server].normalization = Default::default();
+                        }
+                    }
+
+                    // Tables whose values point to other entries can show what an address finally delivers to
+                    let format = self.servers[selected_server].format.format();
+                    if format.recursive() {
+                        ui.collapsing("Expandir una dirección", |ui| {
+                            let mut address: String = get_cache_value("expand_address", ui, None);
+                            if ui
+                                .add(
+                                    egui::TextEdit::singleline(&mut address)
+                                        .hint_text("user@example.com"),
+                                )
+                                .changed()
+                            {
+                                set_cache_value("expand_address", ui, address.clone());
+                            }
+
+                            let address = address.trim();
+                            if !address.is_empty() {
+                                let expansion =
+                                    Resolver::new(format, &self.servers[selected_server].users)
+                                        .expand(address);
+
+                                draw_expansion(ui, &expansion);
+                                ui.label(format!(
+                                    "Destinatarios finales: {}",
+                                    expansion.recipients().join(", ")
+                                ));
+                            }
+                        });
+                    }
+
+                    // Pattern tables can be tried locally, showing the rule postfix would use for an address
+                    let tester = match self.servers[selected_server].format {
+                        MapKind::Regexp => Some(RegexpFormat(Dialect::Posix)),
+                        MapKind::Pcre => Some(RegexpFormat(Dialect::Pcre)),
+                        _ => None,
+                    };
+                    if let Some(tester) = tester {
+                        ui.collapsing("Probar una dirección", |ui| {
+                            // regexp tables are compiled by regcomp(3) in postfix, not by the local engine
+                            if tester.0 == Dialect::Posix {
+                                ui.small(
+                                    "Resultado orientativo: postfix usa expresiones POSIX y aquí se traducen a un motor compatible con PCRE",
+                                );
+                            }
+                            let mut address: String = get_cache_value("test_address", ui, None);
+                            if ui
+                                .add(
+                                    egui::TextEdit::singleline(&mut address)
+                                        .hint_text("user@example.com"),
+                                )
+                                .changed()
+                            {
+                                set_cache_value("test_address", ui, address.clone());
+                            }
+
+                            let address = address.trim();
+                            if !address.is_empty() {
+                                match tester.evaluate(&self.servers[selected_server].users, address)
+                                {
+                                    Ok(Some(rule)) => {
+                                        ui.label(format!("Regla {}: {}", rule.index + 1, rule.key));
+                                        ui.colored_label(
+                                            Color32::GREEN,
+                                            format!("→ {}", rule.result),
+                                        );
+                                    }
+                                    Ok(None) => {
+                                        ui.label("Ninguna regla coincide");
+                                    }
+                                    Err(error) => {
+                                        ui.colored_label(Color32::RED, error);
+                                    }
+                                }
+                            }
+                        });
+                    }
+                }
+
+                // Get the redirections in a variable so it's easyer to type
+                let server_redirections = &self.servers[selected_server].users;
+                // The format knows how to validate keys and values of this server's table
+                let kind = self.servers[selected_server].format;
+                let format = kind.format();
+                // Number of entries, to know which ones can be moved up or down
+                let entry_count = server_redirections.entries().count();
+
+                // Show the items in a vertical scroll area, so it's free to grow as needed
+                egui::ScrollArea::vertical().show(ui, |ui| ui.add_enabled_ui(editable, |ui| {
+                    // Iterate over the entries, in the same order they have in the file
+                    server_redirections
+                        .entries()
+                        .enumerate()
+                        .for_each(|(index, entry)| {
+                            let mail = entry.key();
+                            let redirections = entry.values();
+                            // Widget and cache ids, keys repeat in ordered tables so their rules use the position
+                            let id = if format.ordered() {
+                                format!("#{index}")
+                            } else {
+                                mail.to_owned()
+                            };
+
+                            // Show the data grouped(This is more appealing)
+                            egui::CollapsingHeader::new(mail)
+                                .id_source(("entry", &id))
+                                .show(ui, |ui| {
+                                // Validation error for the rename input, shown below it
+                                let mut rename_error: Option<String> = None;
+
+                                // Make the group allocate the whole horizontal space, so it's uniform
+                                ui.allocate_space(Vec2::new(ui.available_width(), 0.0));
+                                // Horizontal widget to hold the email and the delete button
                                 ui.horizontal(|ui| {
+                                    let mut email: String = get_cache_value(
+                                        &(id.clone() + "e"),
+                                        ui,
+                                        Some(mail.to_owned()),
+                                    );
+                                    if ui.text_edit_singleline(&mut email).changed() {
+                                        set_cache_value(&(id.clone() + "e"), ui, email.clone());
+                                    }
+
+                                    // Only allow renaming to a valid key that isn't already in the list, rules of
+                                    // ordered tables may repeat
+                                    let alias = validate_input(&email, |x| format.validate_key(x))
+                                        .and_then(|alias| match alias {
+                                            Some(alias)
+                                                if alias != mail
+                                                    && !format.ordered()
+                                                    && server_redirections.contains_key(&alias) =>
+                                            {
+                                                Err(format!("'{alias}' ya existe"))
+                                            }
+                                            alias => Ok(alias),
+                                        });
+                                    rename_error = alias.as_ref().err().cloned();
+
+                                    ui.add_enabled_ui(matches!(alias, Ok(Some(_))), |ui| {
+                                        if ui.small_button("Cambiar").clicked() {
+                                            if let Ok(Some(alias)) = &alias {
+                                                modifications.push(Modification::Rename {
+                                                    index,
+                                                    new_email: alias.clone(),
+                                                });
+                                            }
+                                        }
+                                    });
+                                    // Rules of ordered tables can be moved, the first one that matches wins
+                                    if format.ordered() {
+                                        ui.add_enabled_ui(index > 0, |ui| {
+                                            if ui.small_button("⬆").clicked() {
+                                                modifications.push(Modification::Move {
+                                                    from: index,
+                                                    to: index - 1,
+                                                });
+                                            }
+                                        });
+                                        ui.add_enabled_ui(index + 1 < entry_count, |ui| {
+                                            if ui.small_button("⬇").clicked() {
+                                                modifications.push(Modification::Move {
+                                                    from: index,
+                                                    to: index + 1,
+                                                });
+                                            }
+                                        });
+                                    }
+                                    // ui.add_space(40.0);
                                     // Add the delete button
                                     if ui.small_button("❌").clicked() {
-                                        // Just push a modification wich eliminates this entry
-                                        modifications.push(Modification::UpdateEmail {
-                                            email: mail.to_owned(),
-                                            // Filter redirections removing this entry
-                                            redirections: redirections
-                                                .iter()
-                                                .filter_map(|entry| {
-                                                    if entry != redir {
-                                                        Some(entry.to_owned())
-                                                    } else {
-                                                        None
-                                                    }
-                                                })
-                                                .collect(),
-                                        })
+                                        log::trace!("Removing entry");
+                                        modifications.push(Modification::RemoveEmail(index));
                                     }
-                                    // Add the email label
-                                    ui.label(redir);
-                                    ui.add_space(10.0);
                                 });
-                            }
 
-                            // Get a temporary input string that will hold the text for the text input for that email
-                            let mut temp_input: String = get_cache_value(mail, ui, None);
-
-                            ui.separator();
-                            // Add a small label
-                            ui.small("Añadir redirección");
-                            // Then add the text input and a button to add the entry
-                            ui.horizontal(|ui| {
-                                // Update the cached value only when the user changes the value
-                                if (&ui.text_edit_singleline(&mut temp_input)).changed() {
-                                    set_cache_value(mail, ui, temp_input.clone());
-                                };
+                                // Show why the new name can't be used
+                                if let Some(error) = rename_error {
+                                    ui.colored_label(Color32::RED, error);
+                                }
 
-                                // Add the 'add' button only when there is valid data
-                                ui.add_enabled_ui(!temp_input.trim().is_empty(), |ui| {
-                                    if ui.small_button("Añadir").clicked() {
-                                        // Clone the redirections
-                                        let mut redirection = redirections.clone();
+                                ui.add_space(10.0);
+                                ui.label(format.value_label());
 
-                                        // Add the new entry
-                                        redirection.push(temp_input);
+                                // Access and transport tables get an editor that knows the structure of their values
+                                let editor = match kind {
+                                    MapKind::Access => Some(access_editor as ValueEditor),
+                                    MapKind::Transport => Some(transport_editor as ValueEditor),
+                                    _ => None,
+                                };
+                                if let Some(editor) = editor {
+                                    for value in redirections {
+                                        ui.small(value);
+                                    }
 
-                                        // And then push the modification into the list
+                                    if let Some(values) = editor(ui, &id, redirections) {
                                         modifications.push(Modification::UpdateEmail {
-                                            email: mail.to_owned(),
-                                            redirections: redirection,
+                                            index,
+                                            redirections: values,
                                         });
-
-                                        // Clear the cached input text, so it's cleared and ready to accept further input from the user
-                                        set_cache_value(mail, ui, String::new());
                                     }
+                                    return;
+                                }
+
+                                // Iterate over the redirections, adding the entries
+                                for redir in redirections {
+                                    // Horizontal widget to hold the item's label and the delete button
+                                    ui.horizontal(|ui| {
+                                        // Add the delete button
+                                        if ui.small_button("❌").clicked() {
+                                            // Just push a modification wich eliminates this entry
+                                            modifications.push(Modification::UpdateEmail {
+                                                index,
+                                                // Filter redirections removing this entry
+                                                redirections: redirections
+                                                    .iter()
+                                                    .filter_map(|entry| {
+                                                        if entry != redir {
+                                                            Some(entry.to_owned())
+                                                        } else {
+                                                            None
+                                                        }
+                                                    })
+                                                    .collect(),
+                                            })
+                                        }
+                                        // Add the email label
+                                        ui.label(redir);
+                                        ui.add_space(10.0);
+                                    });
+                                }
+
+                                // Get a temporary input string that will hold the text for the text input for that email
+                                let mut temp_input: String = get_cache_value(&id, ui, None);
+                                // Validate the input before allowing the user to add it
+                                let destination =
+                                    validate_input(&temp_input, |x| format.validate_value(x));
+
+                                ui.separator();
+                                // Add a small label, tables with a single value replace it instead of adding more
+                                ui.small(if format.syntax().list_values {
+                                    "Añadir redirección"
+                                } else {
+                                    "Nuevo valor"
+                                });
+                                // Then add the text input and a button to add the entry
+                                ui.horizontal(|ui| {
+                                    // Update the cached value only when the user changes the value
+                                    if ui.text_edit_singleline(&mut temp_input).changed() {
+                                        set_cache_value(&id, ui, temp_input.clone());
+                                    };
+
+                                    // Add the 'add' button only when there is valid data
+                                    ui.add_enabled_ui(matches!(destination, Ok(Some(_))), |ui| {
+                                        if ui.small_button("Añadir").clicked() {
+                                            // Clone the redirections
+                                            let mut redirection = if format.syntax().list_values {
+                                                redirections.to_vec()
+                                            } else {
+                                                vec![]
+                                            };
+
+                                            // Add the new entry
+                                            if let Ok(Some(destination)) = &destination {
+                                                redirection.push(destination.clone());
+                                            }
+
+                                            // And then push the modification into the list
+                                            modifications.push(Modification::UpdateEmail {
+                                                index,
+                                                redirections: redirection,
+                                            });
+
+                                            // Clear the cached input text, so it's cleared and ready to accept further input from the user
+                                            set_cache_value(&id, ui, String::new());
+                                        }
+                                    });
                                 });
+
+                                // Show why the input can't be added
+                                if let Err(error) = &destination {
+                                    ui.colored_label(Color32::RED, error);
+                                }
                             });
-                        });
 
-                        ui.separator();
-                    });
-                });
+                            ui.separator();
+                        });
+                }));
 
                 // If the server's data hasn't arrived yet show a spinner and a label indicating so
                 if self.servers[selected_server].users_status == UsersStatus::Downloading {
                     ui.heading("Esperando información del servidor...");
                     ui.spinner();
-                } else {
+                } else if editable {
                     // Else show an input field so the user can add more redirections
                     ui.label("Añadir redirección");
 
+                    // This holds the user input(cached value)
+                    let mut email: String = get_cache_value("email", ui, None);
+
+                    // Validate the input, new aliases must not be in the list already unless they are rules of an
+                    // ordered table, where the same pattern (or endif) may appear many times
+                    let alias =
+                        validate_input(&email, |x| format.validate_key(x)).and_then(|alias| {
+                            match alias {
+                                Some(alias)
+                                    if !format.ordered()
+                                        && server_redirections.contains_key(&alias) =>
+                                {
+                                    Err(format!("'{alias}' ya existe"))
+                                }
+                                alias => Ok(alias),
+                            }
+                        });
+
                     // The horizontal widget that holds the input and the button
                     ui.horizontal(|ui| {
-                        // This holds the user input(cached value)
-                        let mut email: String = get_cache_value("email", ui, None);
-
                         // Update the value only if the user changes the text
                         if ui.text_edit_singleline(&mut email).changed() {
                             set_cache_value("email", ui, email.clone());
                         }
 
                         // Add the 'Add' button
-                        ui.add_enabled_ui(!email.trim().is_empty(), |ui| {
+                        ui.add_enabled_ui(matches!(alias, Ok(Some(_))), |ui| {
                             if ui.small_button("Añadir").clicked() {
-                                // Add the email to the list and push the modification
-                                modifications.push(Modification::UpdateEmail {
-                                    email: email.clone(),
-                                    redirections: vec![],
-                                });
+                                if let Ok(Some(alias)) = &alias {
+                                    // Add the email to the list and push the modification
+                                    modifications.push(Modification::AddEmail(alias.clone()));
+                                }
                                 // Clear the cached input text
                                 set_cache_value("email", ui, String::new());
                             }
                         });
                     });
+
+                    // Show why the input can't be added
+                    if let Err(error) = &alias {
+                        ui.colored_label(Color32::RED, error);
+                    }
                 }
             }
             // Now the borrow it's out of scope we can modify self.servers once again
 
             // Process the modifications
             for modification in modifications {
+                // Editing a map takes its lock
+                let edited = match &modification {
+                    Modification::RemoveDestination { server, .. } => *server,
+                    _ => selected_server,
+                };
+                if matches!(self.servers[edited].lock, LockStatus::Theirs(_)) {
+                    log::warn!("Not editing {}, someone else holds its lock", self.servers[edited]);
+                    continue;
+                }
+                self.lock_for_editing(edited);
+
                 match modification {
-                    // Email update/insertion modification
+                    // Email insertion modification, appended to the end of the document
+                    Modification::AddEmail(email) => {
+                        self.servers[selected_server].users_mut().push(&email, vec![]);
+                    }
+
+                    // Email update modification, the entry is updated in place
                     Modification::UpdateEmail {
-                        email,
+                        index,
                         redirections,
                     } => {
-                        // Get a mut borrow of the user list
-                        let server_redirections = self.servers[selected_server].users.borrow_mut();
-                        // If there is already an entry for that email
-                        if let Some(redirection) = server_redirections.get_mut(&email) {
-                            // just update the content
-                            *redirection = redirections;
-                        } else {
-                            // else insert it into the hash
-                            server_redirections.insert(email, redirections);
-                        }
+                        self.servers[selected_server].users_mut().set_at(index, redirections);
+                    }
+
+                    // Email rename modification
+                    Modification::Rename { index, new_email } => {
+                        self.servers[selected_server].users_mut().rename_at(index, &new_email);
                     }
 
                     // Email deletion modification
-                    Modification::RemoveEmail(email) => {
-                        // Remove the entry from the hash
-                        self.servers[selected_server].users.remove(&email);
+                    Modification::RemoveEmail(index) => {
+                        // Remove the entry from the document
+                        self.servers[selected_server].users_mut().remove_at(index);
+                    }
+
+                    // Destination removal from the reverse lookup, it may target any server
+                    Modification::RemoveDestination {
+                        server,
+                        email,
+                        destination,
+                    } => {
+                        remove_destination(self.servers[server].users_mut(), &email, &destination);
+                    }
+
+                    // Entry reordering modification
+                    Modification::Move { from, to } => {
+                        self.servers[selected_server].users_mut().move_entry(from, to);
                     }
                 }
             }
@@ -292,3 +1008,71 @@ impl Application {
         Ok(())
     }
 }
+
+// Validate the text of an input field, returning None while the input is empty
+fn validate_input(
+    input: &str,
+    validate: impl Fn(&str) -> Result<(), String>,
+) -> Result<Option<String>, String> {
+    let input = input.trim();
+
+    if input.is_empty() {
+        Ok(None)
+    } else {
+        validate(input).map(|_| Some(input.to_owned()))
+    }
+}
+
+// Draw the expansion of an address as a tree, where every expanded address can be collapsed
+fn draw_expansion(ui: &mut Ui, expansion: &Expansion) {
+    match (&expansion.problem, &expansion.key) {
+        (Some(problem), _) => {
+            ui.colored_label(Color32::RED, format!("{} ⚠ {problem}", expansion.address));
+        }
+        (None, Some(key)) => {
+            // Show the entry used when it isn't the address itself (catch-all entries)
+            let label = if key.eq_ignore_ascii_case(&expansion.address) {
+                expansion.address.clone()
+            } else {
+                format!("{} ({key})", expansion.address)
+            };
+
+            egui::CollapsingHeader::new(label)
+                .default_open(true)
+                .show(ui, |ui| {
+                    for child in expansion.children.iter() {
+                        draw_expansion(ui, child);
+                    }
+                });
+        }
+        (None, None) => {
+            ui.label(format!("📨 {}", expansion.address));
+        }
+    }
+}
+
+// Show the steps of an upload, with the output of the commands they ran
+fn draw_steps(ui: &mut Ui, steps: &[StepResult]) {
+    for step in steps.iter() {
+        let icon = if step.success() { "✅" } else { "❌" };
+        ui.label(format!("{icon} {}", step.step));
+
+        if let Some(output) = &step.output {
+            ui.label(
+                RichText::new(format!(
+                    "$ {} (salida {})",
+                    output.command, output.exit_status
+                ))
+                .monospace(),
+            );
+            for text in [&output.stdout, &output.stderr] {
+                if !text.trim().is_empty() {
+                    ui.label(RichText::new(text.trim()).monospace());
+                }
+            }
+        }
+        if let Some(error) = &step.error {
+            ui.colored_label(Color32::RED, error);
+        }
+    }
+}
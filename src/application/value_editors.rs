@@ -0,0 +1,225 @@
+use egui::{Color32, Ui};
+
+use crate::{
+    application::backend::mapformat::{
+        access::{AccessAction, AccessVerb, TextRule},
+        transport::{Nexthop, TransportRoute, TRANSPORTS},
+    },
+    cache_utils::{get_cache_value, set_cache_value},
+};
+
+// Editors for tables whose values have their own structure, they return the new values when the user applies a change
+pub type ValueEditor = fn(&mut Ui, &str, &[String]) -> Option<Vec<String>>;
+
+// Editor for access(5) actions: the valid actions in a combo box, plus their text when they accept one
+pub fn access_editor(ui: &mut Ui, key: &str, values: &[String]) -> Option<Vec<String>> {
+    // Start from the current action, if it's valid
+    let current = values
+        .first()
+        .and_then(|value| value.parse::<AccessAction>().ok());
+
+    let verb_id = key.to_owned() + "access_verb";
+    let text_id = key.to_owned() + "access_text";
+
+    let mut verb_index: usize = get_cache_value(
+        &verb_id,
+        ui,
+        current
+            .as_ref()
+            .and_then(|action| AccessVerb::ALL.iter().position(|verb| *verb == action.verb)),
+    );
+    let mut text: String = get_cache_value(
+        &text_id,
+        ui,
+        current.as_ref().map(|action| action.text.clone()),
+    );
+    let verb = AccessVerb::ALL[verb_index];
+
+    let mut result = None;
+
+    ui.horizontal(|ui| {
+        // The action selector
+        egui::ComboBox::from_id_source(&verb_id)
+            .selected_text(verb.keyword())
+            .show_ui(ui, |ui| {
+                for (index, verb) in AccessVerb::ALL.iter().enumerate() {
+                    if ui
+                        .selectable_value(&mut verb_index, index, verb.keyword())
+                        .changed()
+                    {
+                        set_cache_value(&verb_id, ui, verb_index);
+                    }
+                }
+            });
+
+        // The text input, only for actions that accept text
+        ui.add_enabled_ui(verb.text_rule() != TextRule::None, |ui| {
+            if ui
+                .add(egui::TextEdit::singleline(&mut text).hint_text(verb.text_hint()))
+                .changed()
+            {
+                set_cache_value(&text_id, ui, text.clone());
+            }
+        });
+    });
+
+    // Build the action and validate it before allowing the user to apply it
+    let text = if verb.text_rule() == TextRule::None {
+        ""
+    } else {
+        &text
+    };
+    let action = AccessAction::new(verb, text);
+
+    match &action {
+        Ok(action) if Some(action) != current.as_ref() => {
+            if ui.small_button("Aplicar").clicked() {
+                result = Some(vec![action.to_string()]);
+            }
+        }
+        Ok(_) => {}
+        Err(error) => {
+            ui.colored_label(Color32::RED, error);
+        }
+    }
+
+    result
+}
+
+// Editor for transport(5) results: the transport in a combo box, plus the nexthop host and port (or text for error/discard)
+pub fn transport_editor(ui: &mut Ui, key: &str, values: &[String]) -> Option<Vec<String>> {
+    // Start from the current route, if it's valid
+    let current = values
+        .first()
+        .and_then(|value| value.parse::<TransportRoute>().ok());
+
+    let transport_id = key.to_owned() + "transport";
+    let host_id = key.to_owned() + "transport_host";
+    let port_id = key.to_owned() + "transport_port";
+    let mx_id = key.to_owned() + "transport_mx";
+
+    // Split the current nexthop into the fields of the editor
+    let (host, port, mx_lookup) = match current.as_ref().map(|route| &route.nexthop) {
+        Some(Nexthop::Host {
+            host,
+            port,
+            mx_lookup,
+        }) => (
+            host.clone(),
+            port.map(|port| port.to_string()).unwrap_or_default(),
+            *mx_lookup,
+        ),
+        Some(Nexthop::Socket(path)) => (format!("unix:{path}"), String::new(), true),
+        Some(Nexthop::Text(text)) => (text.clone(), String::new(), true),
+        Some(Nexthop::Default) | None => (String::new(), String::new(), true),
+    };
+
+    let mut transport: String = get_cache_value(
+        &transport_id,
+        ui,
+        Some(
+            current
+                .as_ref()
+                .map(|route| route.transport.clone())
+                .unwrap_or("smtp".to_owned()),
+        ),
+    );
+    let mut host: String = get_cache_value(&host_id, ui, Some(host));
+    let mut port: String = get_cache_value(&port_id, ui, Some(port));
+    let mut mx_lookup: bool = get_cache_value(&mx_id, ui, Some(mx_lookup));
+
+    // The error and discard transports take text instead of a host
+    let takes_text = transport == "error" || transport == "discard";
+
+    ui.horizontal(|ui| {
+        // The transport selector
+        egui::ComboBox::from_id_source(&transport_id)
+            .selected_text(&transport)
+            .show_ui(ui, |ui| {
+                for name in TRANSPORTS {
+                    if ui
+                        .selectable_value(&mut transport, name.to_owned(), name)
+                        .changed()
+                    {
+                        set_cache_value(&transport_id, ui, transport.clone());
+                    }
+                }
+            });
+
+        let hint = if takes_text {
+            "texto"
+        } else if transport == "lmtp" {
+            "host o unix:/ruta"
+        } else {
+            "host"
+        };
+        if ui
+            .add(
+                egui::TextEdit::singleline(&mut host)
+                    .hint_text(hint)
+                    .desired_width(120.0),
+            )
+            .changed()
+        {
+            set_cache_value(&host_id, ui, host.clone());
+        }
+
+        if !takes_text {
+            ui.label(":");
+            if ui
+                .add(
+                    egui::TextEdit::singleline(&mut port)
+                        .hint_text("puerto")
+                        .desired_width(50.0),
+                )
+                .changed()
+            {
+                set_cache_value(&port_id, ui, port.clone());
+            }
+        }
+    });
+
+    if !takes_text
+        && ui
+            .checkbox(&mut mx_lookup, "Buscar registros MX")
+            .on_hover_text("Sin marcar el host se escribe entre corchetes: [host]")
+            .changed()
+    {
+        set_cache_value(&mx_id, ui, mx_lookup);
+    }
+
+    // Build the nexthop text from the fields
+    let host = host.trim();
+    let port = port.trim();
+    let nexthop = if takes_text || host.is_empty() || host.starts_with("unix:") {
+        host.to_owned()
+    } else {
+        let host = if mx_lookup {
+            host.to_owned()
+        } else {
+            format!("[{host}]")
+        };
+        if port.is_empty() {
+            host
+        } else {
+            format!("{host}:{port}")
+        }
+    };
+
+    // Validate the route before allowing the user to apply it
+    let mut result = None;
+
+    match TransportRoute::new(&transport, &nexthop) {
+        Ok(route) if Some(&route) != current.as_ref() => {
+            if ui.small_button("Aplicar").clicked() {
+                result = Some(vec![route.to_string()]);
+            }
+        }
+        Ok(_) => {}
+        Err(error) => {
+            ui.colored_label(Color32::RED, error);
+        }
+    }
+
+    result
+}
@@ -9,10 +9,7 @@ where
         writer
             .data
             .get_temp_mut_or_insert_with((id.to_owned() + "v").into(), || -> T {
-                match default {
-                    Some(value) => value,
-                    None => T::default(),
-                }
+                default.unwrap_or_default()
             })
             .to_owned()
     })
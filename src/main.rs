@@ -10,6 +10,25 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     log::trace!("Starting");
 
+    // email-forward-manager --import-ssh-config [PATTERN] [CONFIG_PATH] adds the ssh config hosts to config.toml
+    let arguments: Vec<String> = std::env::args().skip(1).collect();
+    if arguments.first().map(String::as_str) == Some("--import-ssh-config") {
+        let pattern = arguments.get(1).map(String::as_str).unwrap_or("*");
+        let config_path = arguments
+            .get(2)
+            .map(String::as_str)
+            .unwrap_or("/etc/postfix/virtual");
+
+        let added = application::import_ssh_config(pattern, config_path)?;
+        if added.is_empty() {
+            println!("No new hosts matching {pattern} in the ssh config");
+        }
+        for alias in added {
+            println!("Added {alias} ({config_path}) to config.toml");
+        }
+        return Ok(());
+    }
+
     eframe::run_native(
         "Email Forwarding Manager",
         eframe::NativeOptions {